@@ -0,0 +1,205 @@
+//! Tar archives holding every file a package's build produced.
+
+use flate2::{Compression as GzLevel, write::GzEncoder};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+use tar::{Builder, Header};
+
+use crate::messages::CompilerArtifact;
+use crate::params::Compression;
+use crate::util::hex;
+
+const MANIFEST_NAME: &str = "manifest.json";
+
+#[derive(Serialize)]
+struct Manifest<'a> {
+    package: &'a str,
+    files: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize)]
+struct ManifestEntry {
+    path: String,
+    kind: &'static str,
+    target: String,
+    crate_types: Vec<String>,
+    size: u64,
+    sha256: String,
+}
+
+/// Packs the outputs of `units`, plus their dep-info files, into a tar archive.
+///
+/// `manifest.json` is the first entry so clients can inspect it without unpacking
+/// the rest. Files are stored flat under their file names.
+pub fn package_archive(
+    package: &str,
+    units: &[CompilerArtifact],
+    compression: Compression,
+) -> io::Result<Vec<u8>> {
+    let mut seen = HashSet::new();
+    let mut entries = Vec::new();
+    let mut contents = Vec::new();
+
+    for unit in units {
+        let dep_info = unit.filenames.iter().filter_map(|path| dep_info_path(path));
+        for path in unit.filenames.iter().cloned().chain(dep_info) {
+            let Some(name) = path.file_name().map(|n| n.to_string_lossy().into_owned()) else {
+                continue;
+            };
+            if !seen.insert(name.clone()) {
+                continue;
+            }
+
+            let data = fs::read(&path)?;
+            let executable = unit.executable.as_ref() == Some(&path);
+            entries.push(ManifestEntry {
+                kind: file_kind(&path, unit, executable),
+                path: name,
+                target: unit.target.name.clone(),
+                crate_types: unit.target.crate_types.clone(),
+                size: data.len() as u64,
+                sha256: hex(&Sha256::digest(&data)),
+            });
+            contents.push((executable, data));
+        }
+    }
+
+    let mut builder = Builder::new(Vec::new());
+
+    let manifest = Manifest {
+        package,
+        files: entries,
+    };
+    append(
+        &mut builder,
+        MANIFEST_NAME,
+        &serde_json::to_vec_pretty(&manifest)?,
+        false,
+    )?;
+
+    for (entry, (executable, data)) in manifest.files.iter().zip(&contents) {
+        append(&mut builder, &entry.path, data, *executable)?;
+    }
+
+    let tar = builder.into_inner()?;
+    match compression {
+        Compression::None => Ok(tar),
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), GzLevel::default());
+            encoder.write_all(&tar)?;
+            encoder.finish()
+        }
+    }
+}
+
+fn append(
+    builder: &mut Builder<Vec<u8>>,
+    name: &str,
+    data: &[u8],
+    executable: bool,
+) -> io::Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(if executable { 0o755 } else { 0o644 });
+    header.set_mtime(0);
+    header.set_cksum();
+    builder.append_data(&mut header, name, data)
+}
+
+/// Cargo writes `<stem>.d` next to each output, with the `lib` prefix dropped in `deps/`.
+fn dep_info_path(output: &Path) -> Option<PathBuf> {
+    let stem = output.file_stem()?.to_string_lossy();
+    let dir = output.parent()?;
+    [stem.as_ref(), stem.strip_prefix("lib").unwrap_or(&stem)]
+        .into_iter()
+        .map(|stem| dir.join(format!("{}.d", stem)))
+        .find(|path| path.is_file())
+}
+
+fn file_kind(path: &Path, unit: &CompilerArtifact, executable: bool) -> &'static str {
+    if executable {
+        return "bin";
+    }
+    let has_kind = |kind: &str| unit.target.kind.iter().any(|k| k == kind);
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("rlib") => "rlib",
+        Some("rmeta") => "rmeta",
+        Some("d") => "dep-info",
+        Some("a") | Some("lib") => "staticlib",
+        Some("so") | Some("dylib") | Some("dll") if has_kind("proc-macro") => "proc-macro",
+        Some("so") | Some("dylib") | Some("dll") if has_kind("cdylib") => "cdylib",
+        Some("so") | Some("dylib") | Some("dll") => "dylib",
+        _ => "other",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn unit(kind: &str) -> CompilerArtifact {
+        serde_json::from_value(serde_json::json!({
+            "package_id": "path+file:///ws/app#0.1.0",
+            "target": { "name": "app", "kind": [kind], "crate_types": [kind] },
+            "filenames": [],
+            "executable": null,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn finds_dep_info_next_to_outputs() {
+        let tmp = TempDir::new().unwrap();
+        let debug = tmp.path().join("debug");
+        fs::create_dir_all(debug.join("deps")).unwrap();
+        for name in ["libapp.d", "app.d", "deps/app-0123abcd.d"] {
+            fs::write(debug.join(name), "").unwrap();
+        }
+
+        let cases = [
+            ("libapp.rlib", Some("libapp.d")),
+            ("libapp.so", Some("libapp.d")),
+            ("app", Some("app.d")),
+            ("deps/libapp-0123abcd.rlib", Some("deps/app-0123abcd.d")),
+            ("deps/app-0123abcd", Some("deps/app-0123abcd.d")),
+            ("libother.rlib", None),
+        ];
+        for (output, expected) in cases {
+            assert_eq!(
+                dep_info_path(&debug.join(output)),
+                expected.map(|name| debug.join(name)),
+                "{output}"
+            );
+        }
+    }
+
+    #[test]
+    fn classifies_output_files() {
+        let cases = [
+            ("libapp.rlib", "lib", false, "rlib"),
+            ("libapp.rmeta", "lib", false, "rmeta"),
+            ("app.d", "lib", false, "dep-info"),
+            ("libapp.a", "staticlib", false, "staticlib"),
+            ("libapp.so", "dylib", false, "dylib"),
+            ("libapp.dylib", "cdylib", false, "cdylib"),
+            ("app.dll", "proc-macro", false, "proc-macro"),
+            ("app", "bin", true, "bin"),
+            ("app.exe", "bin", true, "bin"),
+            ("app.pdb", "bin", false, "other"),
+        ];
+        for (path, kind, executable, expected) in cases {
+            assert_eq!(
+                file_kind(Path::new(path), &unit(kind), executable),
+                expected,
+                "{path}"
+            );
+        }
+    }
+}
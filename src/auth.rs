@@ -0,0 +1,619 @@
+//! Request authentication.
+//!
+//! Two schemes are supported, each enabled by pointing the worker at a key file:
+//!
+//! - `Authorization: Bearer <token>`, checked against a file of static tokens.
+//! - `Authorization: HMAC <key id>:<signature>`, an HMAC-SHA256 over the method, path,
+//!   query, an `X-Timestamp`, an `X-Nonce` and the body's `X-Content-Sha256`. The
+//!   timestamp must be close to the worker's clock and each nonce is accepted once,
+//!   so a captured request cannot be replayed. The body is checked against its
+//!   digest as the handler reads it.
+//!
+//! Key files are read again on `SIGHUP`, so keys can be rotated without a restart:
+//! add the new key, move clients over, then remove the old one.
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use hmac::{Hmac, Mac};
+use hyper::body::{Body as HttpBody, Frame, SizeHint};
+use sha2::{Digest, Sha256};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex, RwLock},
+    task::{Context, Poll, ready},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{error, info, warn};
+
+use crate::AppState;
+use crate::config::Config;
+use crate::handlers::error_response;
+
+/// Routes that answer without credentials, for load balancers and orchestrators.
+const PUBLIC_PATHS: &[&str] = &["/healthz", "/readyz"];
+
+/// Longest nonce a signed request may carry.
+const MAX_NONCE_LEN: usize = 128;
+
+/// One way of proving who sent a request, selected by its `Authorization` scheme.
+pub trait Authenticator: Send + Sync {
+    /// The `Authorization` scheme this authenticator handles, e.g. `Bearer`.
+    fn scheme(&self) -> &'static str;
+
+    /// Checks the credentials that follow the scheme in the `Authorization` header.
+    /// The request may be returned with its body wrapped for further checks.
+    fn authenticate(&self, credentials: &str, req: Request) -> Result<Request, AuthError>;
+
+    /// Reads the keys again from where they were loaded.
+    fn reload(&self) -> io::Result<()>;
+}
+
+pub struct AuthError(String);
+
+impl AuthError {
+    fn new(reason: &str) -> AuthError {
+        AuthError(reason.to_string())
+    }
+}
+
+pub struct Auth {
+    authenticators: Vec<Box<dyn Authenticator>>,
+}
+
+impl Auth {
+    /// Loads the configured key files. Returns `None` when authentication is off.
+    pub fn from_config(config: &Config) -> io::Result<Option<Auth>> {
+        let mut authenticators: Vec<Box<dyn Authenticator>> = Vec::new();
+        if let Some(path) = &config.auth_tokens_file {
+            authenticators.push(Box::new(BearerTokens::load(path.clone())?));
+        }
+        if let Some(path) = &config.auth_hmac_keys_file {
+            authenticators.push(Box::new(HmacKeys::load(
+                path.clone(),
+                config.auth_max_skew_secs,
+            )?));
+        }
+        Ok((!authenticators.is_empty()).then_some(Auth { authenticators }))
+    }
+
+    pub fn schemes(&self) -> Vec<&'static str> {
+        self.authenticators.iter().map(|a| a.scheme()).collect()
+    }
+
+    /// Reloads every key file. A file that fails to load keeps its previous keys.
+    pub fn reload(&self) {
+        for authenticator in &self.authenticators {
+            match authenticator.reload() {
+                Ok(()) => info!(scheme = authenticator.scheme(), "Reloaded keys"),
+                Err(e) => error!(
+                    scheme = authenticator.scheme(),
+                    error = %e,
+                    "Failed to reload keys, keeping the previous ones"
+                ),
+            }
+        }
+    }
+
+    fn authenticate(&self, req: Request) -> Result<Request, AuthError> {
+        if PUBLIC_PATHS.contains(&req.uri().path()) {
+            return Ok(req);
+        }
+        let header = req
+            .headers()
+            .get("Authorization")
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| AuthError::new("Missing Authorization header"))?
+            .to_string();
+        let (scheme, credentials) = header
+            .split_once(' ')
+            .ok_or_else(|| AuthError::new("Malformed Authorization header"))?;
+        let authenticator = self
+            .authenticators
+            .iter()
+            .find(|a| a.scheme().eq_ignore_ascii_case(scheme))
+            .ok_or_else(|| AuthError::new("Unsupported authorization scheme"))?;
+        authenticator.authenticate(credentials.trim(), req)
+    }
+}
+
+/// Rejects requests without valid credentials when authentication is configured.
+pub async fn require_auth(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(auth) = &state.auth else {
+        return next.run(req).await;
+    };
+
+    match auth.authenticate(req) {
+        Ok(req) => next.run(req).await,
+        Err(AuthError(reason)) => {
+            warn!(%reason, "Rejected unauthenticated request");
+            let mut response = error_response(StatusCode::UNAUTHORIZED, &reason);
+            let challenge = auth.schemes().join(", ");
+            response
+                .headers_mut()
+                .insert("WWW-Authenticate", challenge.parse().unwrap());
+            response
+        }
+    }
+}
+
+/// Reloads the key files whenever the worker receives `SIGHUP`.
+pub async fn reload_on_sighup(state: Arc<AppState>) {
+    let Some(auth) = &state.auth else {
+        return;
+    };
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("Failed to install SIGHUP handler");
+    while hangup.recv().await.is_some() {
+        auth.reload();
+    }
+}
+
+/// Static tokens, one per line; blank lines and `#` comments are ignored.
+struct BearerTokens {
+    path: PathBuf,
+    /// SHA-256 of each token, so lookups do not compare secrets byte by byte.
+    digests: RwLock<HashSet<[u8; 32]>>,
+}
+
+impl BearerTokens {
+    fn load(path: PathBuf) -> io::Result<BearerTokens> {
+        let digests = read_tokens(&path)?;
+        Ok(BearerTokens {
+            path,
+            digests: RwLock::new(digests),
+        })
+    }
+}
+
+impl Authenticator for BearerTokens {
+    fn scheme(&self) -> &'static str {
+        "Bearer"
+    }
+
+    fn authenticate(&self, credentials: &str, req: Request) -> Result<Request, AuthError> {
+        let digest: [u8; 32] = Sha256::digest(credentials.as_bytes()).into();
+        if self.digests.read().unwrap().contains(&digest) {
+            Ok(req)
+        } else {
+            Err(AuthError::new("Invalid bearer token"))
+        }
+    }
+
+    fn reload(&self) -> io::Result<()> {
+        *self.digests.write().unwrap() = read_tokens(&self.path)?;
+        Ok(())
+    }
+}
+
+fn read_tokens(path: &Path) -> io::Result<HashSet<[u8; 32]>> {
+    Ok(key_lines(&fs::read_to_string(path)?)
+        .map(|token| Sha256::digest(token.as_bytes()).into())
+        .collect())
+}
+
+/// Shared secrets as `<key id> <secret>` lines; blank lines and `#` comments are
+/// ignored. Several keys may be valid at once, which is how keys are rotated.
+struct HmacKeys {
+    path: PathBuf,
+    keys: RwLock<HashMap<String, Vec<u8>>>,
+    max_skew_secs: u64,
+    nonces: Mutex<SeenNonces>,
+}
+
+impl HmacKeys {
+    fn load(path: PathBuf, max_skew_secs: u64) -> io::Result<HmacKeys> {
+        let keys = read_hmac_keys(&path)?;
+        Ok(HmacKeys {
+            path,
+            keys: RwLock::new(keys),
+            max_skew_secs,
+            nonces: Mutex::new(SeenNonces::default()),
+        })
+    }
+}
+
+impl Authenticator for HmacKeys {
+    fn scheme(&self) -> &'static str {
+        "HMAC"
+    }
+
+    fn authenticate(&self, credentials: &str, req: Request) -> Result<Request, AuthError> {
+        let (key_id, signature) = credentials
+            .split_once(':')
+            .ok_or_else(|| AuthError::new("Malformed HMAC credentials"))?;
+        let signature =
+            decode_hex(signature).ok_or_else(|| AuthError::new("Malformed HMAC signature"))?;
+
+        let headers = req.headers();
+        let timestamp = header(headers, "X-Timestamp")?;
+        let nonce = header(headers, "X-Nonce")?;
+        let content_sha256 = header(headers, "X-Content-Sha256")?;
+        let expected_digest: [u8; 32] = decode_hex(content_sha256)
+            .and_then(|digest| digest.try_into().ok())
+            .ok_or_else(|| AuthError::new("Malformed X-Content-Sha256"))?;
+        if nonce.is_empty() || nonce.len() > MAX_NONCE_LEN {
+            return Err(AuthError::new("Malformed X-Nonce"));
+        }
+
+        let sent_at: u64 = timestamp
+            .parse()
+            .map_err(|_| AuthError::new("Malformed X-Timestamp"))?;
+        let now = unix_now();
+        if now.abs_diff(sent_at) > self.max_skew_secs {
+            return Err(AuthError::new(
+                "Request timestamp is too far from the worker's clock",
+            ));
+        }
+
+        let canonical = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            req.method(),
+            req.uri().path(),
+            req.uri().query().unwrap_or(""),
+            timestamp,
+            nonce,
+            content_sha256,
+        );
+        {
+            let keys = self.keys.read().unwrap();
+            let secret = keys
+                .get(key_id)
+                .ok_or_else(|| AuthError::new("Unknown HMAC key"))?;
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes any key size");
+            mac.update(canonical.as_bytes());
+            mac.verify_slice(&signature)
+                .map_err(|_| AuthError::new("Invalid HMAC signature"))?;
+        }
+
+        // Only a correctly signed request may use up a nonce. It is remembered for as
+        // long as its timestamp passes the clock check.
+        let nonce = format!("{}:{}", key_id, nonce);
+        if !self
+            .nonces
+            .lock()
+            .unwrap()
+            .insert(nonce, sent_at + self.max_skew_secs, now)
+        {
+            return Err(AuthError::new("Nonce has already been used"));
+        }
+
+        Ok(req.map(|body| {
+            Body::new(VerifiedBody {
+                inner: body,
+                hasher: Some(Sha256::new()),
+                expected: expected_digest,
+            })
+        }))
+    }
+
+    fn reload(&self) -> io::Result<()> {
+        *self.keys.write().unwrap() = read_hmac_keys(&self.path)?;
+        Ok(())
+    }
+}
+
+fn read_hmac_keys(path: &Path) -> io::Result<HashMap<String, Vec<u8>>> {
+    key_lines(&fs::read_to_string(path)?)
+        .map(|line| match line.split_once(char::is_whitespace) {
+            Some((id, secret)) if !id.contains(':') && !secret.trim().is_empty() => {
+                Ok((id.to_string(), secret.trim().as_bytes().to_vec()))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected `<key id> <secret>` in {}", path.display()),
+            )),
+        })
+        .collect()
+}
+
+/// Nonces of accepted requests, remembered until their timestamps could no longer
+/// pass the clock check.
+#[derive(Default)]
+struct SeenNonces {
+    seen: HashSet<String>,
+    /// Soonest expiry first. A nonce expiring at `t` is still replayable at `t`
+    /// itself, so it is only forgotten once `now` is past it.
+    expiries: BinaryHeap<Reverse<(u64, String)>>,
+}
+
+impl SeenNonces {
+    /// Records a nonce. Returns `false` if it was already seen.
+    fn insert(&mut self, nonce: String, expires_at: u64, now: u64) -> bool {
+        while let Some(Reverse((expiry, _))) = self.expiries.peek()
+            && *expiry < now
+        {
+            let Reverse((_, expired)) = self.expiries.pop().unwrap();
+            self.seen.remove(&expired);
+        }
+        if !self.seen.insert(nonce.clone()) {
+            return false;
+        }
+        self.expiries.push(Reverse((expires_at, nonce)));
+        true
+    }
+}
+
+/// A request body that fails at its end unless it matches the signed digest.
+struct VerifiedBody {
+    inner: Body,
+    hasher: Option<Sha256>,
+    expected: [u8; 32],
+}
+
+impl HttpBody for VerifiedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let this = &mut *self;
+        match ready!(Pin::new(&mut this.inner).poll_frame(cx)) {
+            Some(Ok(frame)) => {
+                if let (Some(hasher), Some(data)) = (this.hasher.as_mut(), frame.data_ref()) {
+                    hasher.update(data);
+                }
+                Poll::Ready(Some(Ok(frame)))
+            }
+            Some(Err(e)) => Poll::Ready(Some(Err(e))),
+            None => {
+                let matches = this
+                    .hasher
+                    .take()
+                    .is_none_or(|hasher| hasher.finalize()[..] == this.expected);
+                if matches {
+                    Poll::Ready(None)
+                } else {
+                    Poll::Ready(Some(Err(axum::Error::new(
+                        "request body does not match X-Content-Sha256",
+                    ))))
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Result<&'a str, AuthError> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| AuthError(format!("Missing {} header", name)))
+}
+
+/// Non-empty lines that are not `#` comments, trimmed.
+fn key_lines(contents: &str) -> impl Iterator<Item = &str> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::hex;
+    use tempfile::TempDir;
+
+    const SKEW: u64 = 300;
+
+    fn key_file(dir: &TempDir, name: &str, contents: &str) -> PathBuf {
+        let path = dir.path().join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn hmac_keys(dir: &TempDir) -> HmacKeys {
+        HmacKeys::load(
+            key_file(dir, "hmac", "# rotated monthly\nk1 secret-one\n"),
+            SKEW,
+        )
+        .unwrap()
+    }
+
+    /// A request signed the way clients sign them.
+    fn signed(key_id: &str, secret: &str, sent_at: u64, nonce: &str, body: &str) -> Request {
+        let digest = hex(&Sha256::digest(body.as_bytes()));
+        let canonical = format!(
+            "POST\n/jobs\ncrate_name=foo\n{}\n{}\n{}",
+            sent_at, nonce, digest
+        );
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(canonical.as_bytes());
+        let signature = hex(&mac.finalize().into_bytes());
+
+        Request::builder()
+            .method("POST")
+            .uri("/jobs?crate_name=foo")
+            .header("Authorization", format!("HMAC {}:{}", key_id, signature))
+            .header("X-Timestamp", sent_at.to_string())
+            .header("X-Nonce", nonce)
+            .header("X-Content-Sha256", digest)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    fn check(keys: &HmacKeys, req: Request) -> Result<Request, String> {
+        let credentials =
+            req.headers()["Authorization"].to_str().unwrap()["HMAC ".len()..].to_string();
+        keys.authenticate(&credentials, req)
+            .map_err(|AuthError(reason)| reason)
+    }
+
+    fn bearer(path: &str, token: Option<&str>) -> Request {
+        let mut req = Request::builder().uri(path);
+        if let Some(token) = token {
+            req = req.header("Authorization", format!("Bearer {}", token));
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn accepts_a_correct_signature() {
+        let dir = TempDir::new().unwrap();
+        let keys = hmac_keys(&dir);
+        assert!(check(&keys, signed("k1", "secret-one", unix_now(), "n1", "body")).is_ok());
+    }
+
+    #[test]
+    fn rejects_a_wrong_or_unknown_key() {
+        let dir = TempDir::new().unwrap();
+        let keys = hmac_keys(&dir);
+        let now = unix_now();
+        assert_eq!(
+            check(&keys, signed("k1", "not-the-secret", now, "n1", "")).unwrap_err(),
+            "Invalid HMAC signature"
+        );
+        assert_eq!(
+            check(&keys, signed("k2", "secret-one", now, "n2", "")).unwrap_err(),
+            "Unknown HMAC key"
+        );
+    }
+
+    #[test]
+    fn rejects_timestamps_outside_the_skew() {
+        let dir = TempDir::new().unwrap();
+        let keys = hmac_keys(&dir);
+        let now = unix_now();
+        for sent_at in [now - SKEW - 5, now + SKEW + 5] {
+            assert_eq!(
+                check(&keys, signed("k1", "secret-one", sent_at, "n", "")).unwrap_err(),
+                "Request timestamp is too far from the worker's clock"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_a_reused_nonce() {
+        let dir = TempDir::new().unwrap();
+        let keys = hmac_keys(&dir);
+        let now = unix_now();
+        assert!(check(&keys, signed("k1", "secret-one", now, "n1", "")).is_ok());
+        assert_eq!(
+            check(&keys, signed("k1", "secret-one", now, "n1", "")).unwrap_err(),
+            "Nonce has already been used"
+        );
+    }
+
+    #[test]
+    fn remembers_nonces_while_their_timestamp_is_valid() {
+        let mut nonces = SeenNonces::default();
+        // Sent at the edge of the future skew: it passes the clock check until
+        // `sent_at + SKEW`, inclusive.
+        let now = 1_000;
+        let sent_at = now + SKEW;
+        assert!(nonces.insert("n".to_string(), sent_at + SKEW, now));
+        assert!(!nonces.insert("n".to_string(), sent_at + SKEW, sent_at + SKEW));
+        assert!(nonces.insert("n".to_string(), sent_at + SKEW, sent_at + SKEW + 1));
+
+        // Expiries arrive out of order when clocks differ.
+        let mut nonces = SeenNonces::default();
+        assert!(nonces.insert("late".to_string(), 2_000, 1_000));
+        assert!(nonces.insert("early".to_string(), 1_500, 1_000));
+        assert!(!nonces.insert("early".to_string(), 1_500, 1_500));
+        assert!(nonces.insert("other".to_string(), 2_500, 1_501));
+        assert!(!nonces.insert("late".to_string(), 2_000, 1_501));
+    }
+
+    #[tokio::test]
+    async fn rejects_a_body_that_does_not_match_its_digest() {
+        let dir = TempDir::new().unwrap();
+        let keys = hmac_keys(&dir);
+        let req = check(
+            &keys,
+            signed("k1", "secret-one", unix_now(), "n1", "signed"),
+        )
+        .unwrap();
+        let body = axum::body::to_bytes(req.into_body(), usize::MAX).await;
+        assert_eq!(body.unwrap(), "signed");
+
+        let mut req = signed("k1", "secret-one", unix_now(), "n2", "signed");
+        *req.body_mut() = Body::from("tampered");
+        let req = check(&keys, req).unwrap();
+        assert!(
+            axum::body::to_bytes(req.into_body(), usize::MAX)
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn checks_bearer_tokens_and_skips_public_paths() {
+        let dir = TempDir::new().unwrap();
+        let auth = Auth {
+            authenticators: vec![Box::new(
+                BearerTokens::load(key_file(&dir, "tokens", "token-a\n\n# old\ntoken-b\n"))
+                    .unwrap(),
+            )],
+        };
+
+        assert!(auth.authenticate(bearer("/jobs", Some("token-a"))).is_ok());
+        assert!(auth.authenticate(bearer("/jobs", Some("token-b"))).is_ok());
+        assert!(auth.authenticate(bearer("/jobs", Some("token-c"))).is_err());
+        assert!(auth.authenticate(bearer("/jobs", Some("# old"))).is_err());
+        assert!(auth.authenticate(bearer("/jobs", None)).is_err());
+        assert!(auth.authenticate(bearer("/metrics", None)).is_err());
+        for path in PUBLIC_PATHS {
+            assert!(auth.authenticate(bearer(path, None)).is_ok());
+        }
+    }
+
+    #[test]
+    fn failed_reload_keeps_the_previous_keys() {
+        let dir = TempDir::new().unwrap();
+        let tokens = key_file(&dir, "tokens", "token-a\n");
+        let hmac = key_file(&dir, "hmac", "k1 secret-one\n");
+        let auth = Auth {
+            authenticators: vec![
+                Box::new(BearerTokens::load(tokens.clone()).unwrap()),
+                Box::new(HmacKeys::load(hmac.clone(), SKEW).unwrap()),
+            ],
+        };
+
+        fs::write(&tokens, "token-b\n").unwrap();
+        fs::write(&hmac, "k1\n").unwrap();
+        auth.reload();
+        assert!(auth.authenticate(bearer("/jobs", Some("token-a"))).is_err());
+        assert!(auth.authenticate(bearer("/jobs", Some("token-b"))).is_ok());
+        let req = signed("k1", "secret-one", unix_now(), "n1", "");
+        assert!(auth.authenticate(req).is_ok());
+
+        fs::remove_file(&tokens).unwrap();
+        auth.reload();
+        assert!(auth.authenticate(bearer("/jobs", Some("token-b"))).is_ok());
+    }
+}
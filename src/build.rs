@@ -0,0 +1,552 @@
+use axum::{body::Bytes, http::StatusCode};
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    process::{Child, Command},
+    sync::watch,
+};
+use tracing::{error, info, warn};
+
+use crate::archive;
+use crate::limits::{Limit, Limiter};
+use crate::logs::{JobLog, LogStream};
+use crate::messages::{self, CargoMessage, CompilerArtifact, DiagnosticReport};
+use crate::params::{CompileParams, Compression, OutputMode};
+use crate::sandbox::Sandbox;
+
+/// Variables cargo inherits from the worker's environment to find its toolchain.
+const TOOLCHAIN_ENV_VARS: &[&str] = &[
+    "PATH",
+    "HOME",
+    "CARGO_HOME",
+    "RUSTUP_HOME",
+    "RUSTUP_TOOLCHAIN",
+    "LANG",
+    "LANGUAGE",
+    "LC_ALL",
+    "LC_CTYPE",
+    "LC_MESSAGES",
+];
+
+/// Variables that change what cargo and rustc produce. Builds inherit them, and the
+/// build cache hashes them into every key.
+const ARTIFACT_ENV_VARS: &[&str] = &[
+    "RUSTFLAGS",
+    "RUSTDOCFLAGS",
+    "CARGO_ENCODED_RUSTFLAGS",
+    "RUSTC",
+    "RUSTC_WRAPPER",
+    "RUSTC_WORKSPACE_WRAPPER",
+    "CC",
+    "CXX",
+    "AR",
+    "CFLAGS",
+    "CXXFLAGS",
+    "LDFLAGS",
+];
+const ARTIFACT_ENV_PREFIXES: &[&str] = &["CARGO_BUILD_", "CARGO_PROFILE_", "CARGO_TARGET_"];
+
+/// Whether `name` is one of the [`ARTIFACT_ENV_VARS`] or [`ARTIFACT_ENV_PREFIXES`].
+///
+/// `CARGO_TARGET_DIR` is excluded: the worker chooses where each build writes.
+pub fn affects_artifacts(name: &str) -> bool {
+    ARTIFACT_ENV_VARS.contains(&name)
+        || (ARTIFACT_ENV_PREFIXES
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+            && name != "CARGO_TARGET_DIR")
+}
+
+/// The part of `vars` cargo inherits. Everything else, such as credentials the
+/// worker was started with, is withheld from builds.
+fn build_env(
+    vars: impl IntoIterator<Item = (OsString, OsString)>,
+) -> impl Iterator<Item = (OsString, OsString)> {
+    vars.into_iter().filter(|(name, _)| {
+        name.to_str()
+            .is_some_and(|name| TOOLCHAIN_ENV_VARS.contains(&name) || affects_artifacts(name))
+    })
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArtifactKind {
+    Rlib,
+    /// A library built without an rlib, e.g. a proc-macro or `cdylib`-only crate.
+    Library,
+    Binary,
+    /// Every output of the package, see [`archive::package_archive`].
+    Archive(Compression),
+}
+
+/// The response body for a successful build.
+pub struct Artifact {
+    pub kind: ArtifactKind,
+    pub filename: String,
+    pub data: Bytes,
+}
+
+pub enum BuildError {
+    /// The build was aborted through its cancellation channel.
+    Cancelled,
+    /// Cargo was still running when the build's timeout expired.
+    TimedOut(Duration),
+    /// The build was killed by, or failed because of, a resource limit.
+    ResourceExhausted(Limit),
+    /// Cargo ran but the build did not succeed.
+    CompileFailed(DiagnosticReport),
+    Failed(StatusCode, String),
+}
+
+/// Where and under which restrictions a job's cargo runs.
+pub struct BuildContext<'a> {
+    pub job_id: &'a str,
+    pub workspace: &'a Path,
+    /// Overrides cargo's default `<workspace>/target`.
+    pub target_dir: Option<&'a Path>,
+    /// When set, the workspace and target directory are the only paths cargo can write.
+    pub sandbox: Option<&'a Sandbox>,
+    pub limiter: &'a Limiter,
+}
+
+/// The `cargo build` invocation for `params`, with an environment built from `vars`.
+fn cargo_command(
+    workspace: &Path,
+    target_dir: Option<&Path>,
+    params: &CompileParams,
+    vars: impl IntoIterator<Item = (OsString, OsString)>,
+) -> Command {
+    let mut command = Command::new("cargo");
+    command
+        .env_clear()
+        .envs(build_env(vars))
+        .args(params.cargo_args())
+        .arg("--message-format=json-diagnostic-rendered-ansi")
+        .current_dir(workspace)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // Its own process group, so rustc and build scripts can be killed with it.
+        .process_group(0)
+        .kill_on_drop(true);
+    if let Some(target_dir) = target_dir {
+        command.env("CARGO_TARGET_DIR", target_dir);
+    }
+    command
+}
+
+/// Runs `cargo build` for `params` inside an unpacked workspace and collects its output.
+///
+/// Cargo's output is forwarded line by line to `log` while it runs. Cargo, and
+/// everything it started, is killed as soon as `cancel` flips to `true` or after
+/// `timeout`.
+pub async fn run_build(
+    ctx: BuildContext<'_>,
+    params: &CompileParams,
+    log: Arc<JobLog>,
+    mut cancel: watch::Receiver<bool>,
+    timeout: Duration,
+) -> Result<Artifact, BuildError> {
+    let BuildContext {
+        job_id,
+        workspace,
+        target_dir,
+        sandbox,
+        limiter,
+    } = ctx;
+
+    let mut command = cargo_command(workspace, target_dir, params, std::env::vars_os());
+    let limits = match limiter.apply(&mut command, job_id) {
+        Ok(limits) => limits,
+        Err(e) => {
+            error!(error = ?e, "Failed to set up build limits");
+            return Err(BuildError::Failed(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to set up build limits".to_string(),
+            ));
+        }
+    };
+    if let Some(sandbox) = sandbox {
+        let writable: Vec<&Path> = std::iter::once(workspace).chain(target_dir).collect();
+        if let Err(e) = sandbox.apply(&mut command, workspace, &writable) {
+            error!(error = ?e, "Failed to prepare build sandbox");
+            return Err(BuildError::Failed(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to prepare build sandbox".to_string(),
+            ));
+        }
+    }
+
+    let disk_dirs: Vec<PathBuf> = std::iter::once(workspace)
+        .chain(target_dir)
+        .map(Path::to_path_buf)
+        .collect();
+    let disk_baseline = limits.disk_usage(&disk_dirs).await;
+
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            error!(error = ?e, "Failed to run cargo");
+            return Err(BuildError::Failed(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Cargo execution failed".to_string(),
+            ));
+        }
+    };
+
+    let stdout_pipe = child.stdout.take().expect("stdout is piped");
+    let stderr_pipe = child.stderr.take().expect("stderr is piped");
+    let stdout_task = tokio::spawn(forward_lines(stdout_pipe, LogStream::Stdout, log.clone()));
+    let stderr_task = tokio::spawn(forward_lines(stderr_pipe, LogStream::Stderr, log));
+
+    let started = Instant::now();
+    let status = tokio::select! {
+        status = child.wait() => status,
+        _ = cancelled(&mut cancel) => {
+            kill_process_group(&mut child).await;
+            info!(build_ms = started.elapsed().as_millis() as u64, "Cargo killed on cancellation");
+            return Err(BuildError::Cancelled);
+        }
+        _ = tokio::time::sleep(timeout) => {
+            kill_process_group(&mut child).await;
+            warn!(timeout_secs = timeout.as_secs(), "Cargo exceeded its timeout");
+            return Err(BuildError::TimedOut(timeout));
+        }
+        limit = limits.disk_exceeded(disk_dirs, disk_baseline) => {
+            kill_process_group(&mut child).await;
+            warn!(%limit, "Build exceeded a resource limit");
+            return Err(BuildError::ResourceExhausted(limit));
+        }
+    };
+    if let Ok(status) = &status {
+        info!(
+            exit_status = %status,
+            build_ms = started.elapsed().as_millis() as u64,
+            "Cargo exited"
+        );
+    }
+
+    let stdout = stdout_task.await.unwrap_or_default();
+    let stderr = stderr_task.await.unwrap_or_default();
+
+    match status {
+        Ok(s) if s.success() => {
+            // Reading outputs and building archives is file I/O, kept off the runtime.
+            let params = params.clone();
+            match tokio::task::spawn_blocking(move || find_artifact(&stdout, &params)).await {
+                Ok(result) => result,
+                Err(e) => {
+                    error!(error = ?e, "Collecting build output panicked");
+                    Err(BuildError::Failed(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "Failed to read build output".to_string(),
+                    ))
+                }
+            }
+        }
+
+        Ok(_) if let Some(limit) = limits.tripped(&stderr) => {
+            warn!(%limit, "Build exceeded a resource limit");
+            Err(BuildError::ResourceExhausted(limit))
+        }
+
+        Ok(_) => {
+            let report = DiagnosticReport::from_output(&stdout, stderr);
+            warn!(
+                errors = report.summary.errors,
+                warnings = report.summary.warnings,
+                "Compilation failed"
+            );
+            Err(BuildError::CompileFailed(report))
+        }
+
+        Err(e) => {
+            error!(error = ?e, "Failed to wait for cargo");
+            Err(BuildError::Failed(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Cargo execution failed".to_string(),
+            ))
+        }
+    }
+}
+
+/// Kills every process in the child's group, then reaps the child.
+///
+/// The group is signalled before the child is reaped, so its ID cannot have been
+/// reused by an unrelated group yet.
+async fn kill_process_group(child: &mut Child) {
+    if let Some(pid) = child.id() {
+        // SAFETY: killpg only sends a signal; it has no memory safety requirements.
+        unsafe {
+            libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+        }
+    }
+    let _ = child.kill().await;
+}
+
+/// Adds a line of cargo's output to the live log.
+///
+/// Cargo's stdout carries JSON messages: compiler diagnostics are published as the
+/// text rustc renders for them, and the other messages, which only the worker reads,
+/// are left out.
+fn publish(log: &JobLog, stream: LogStream, line: &str) {
+    if matches!(stream, LogStream::Stdout) {
+        match serde_json::from_str(line) {
+            Ok(CargoMessage::CompilerMessage { message, .. }) => {
+                if let Some(rendered) = message.rendered {
+                    log.push(LogStream::Diagnostic, rendered.trim_end().to_string());
+                }
+                return;
+            }
+            Ok(_) => return,
+            Err(_) => {}
+        }
+    }
+    log.push(stream, line.to_string());
+}
+
+/// Publishes each line of `pipe` to `log` and returns the complete output.
+async fn forward_lines(
+    pipe: impl AsyncRead + Unpin,
+    stream: LogStream,
+    log: Arc<JobLog>,
+) -> String {
+    let mut reader = BufReader::new(pipe);
+    let mut output = String::new();
+    let mut buf = Vec::new();
+
+    loop {
+        buf.clear();
+        match reader.read_until(b'\n', &mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                let line = String::from_utf8_lossy(&buf);
+                output.push_str(&line);
+                publish(&log, stream, line.trim_end_matches(['\n', '\r']));
+            }
+        }
+    }
+
+    output
+}
+
+/// Collects the requested package's outputs from the `compiler-artifact` messages
+/// cargo emitted, either as its primary file or as an archive of all of them.
+fn find_artifact(stdout: &str, params: &CompileParams) -> Result<Artifact, BuildError> {
+    let units: Vec<CompilerArtifact> = messages::compiler_artifacts(stdout)
+        .filter(|unit| unit.package_name() == params.crate_name && !unit.target.is_build_script())
+        .collect();
+
+    let mut package_ids: Vec<&str> = units.iter().map(|unit| unit.package_id.as_str()).collect();
+    package_ids.sort_unstable();
+    package_ids.dedup();
+    if package_ids.len() > 1 {
+        return Err(ambiguous(params, &package_ids));
+    }
+
+    match params.output {
+        OutputMode::File => primary_artifact(&units, params),
+        OutputMode::Archive => archive_artifact(&units, params),
+    }
+}
+
+fn archive_artifact(
+    units: &[CompilerArtifact],
+    params: &CompileParams,
+) -> Result<Artifact, BuildError> {
+    if units.is_empty() {
+        error!(crate_name = %params.crate_name, "No output file found");
+        return Err(BuildError::Failed(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "No output file found".to_string(),
+        ));
+    }
+
+    match archive::package_archive(&params.crate_name, units, params.compression) {
+        Ok(data) => {
+            let extension = match params.compression {
+                Compression::None => "tar",
+                Compression::Gzip => "tar.gz",
+            };
+            Ok(Artifact {
+                kind: ArtifactKind::Archive(params.compression),
+                filename: format!("{}.{}", params.crate_name, extension),
+                data: data.into(),
+            })
+        }
+        Err(e) => {
+            error!(crate_name = %params.crate_name, error = ?e, "Failed to archive outputs");
+            Err(BuildError::Failed(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to archive build output".to_string(),
+            ))
+        }
+    }
+}
+
+/// Picks the single file to return. Libraries take precedence over binaries.
+fn primary_artifact(
+    units: &[CompilerArtifact],
+    params: &CompileParams,
+) -> Result<Artifact, BuildError> {
+    let lib_files: Vec<&PathBuf> = units
+        .iter()
+        .filter(|unit| unit.target.is_lib())
+        .flat_map(|unit| &unit.filenames)
+        .filter(|path| path.extension().is_none_or(|ext| ext != "rmeta"))
+        .collect();
+
+    if let Some(rlib) = lib_files
+        .iter()
+        .find(|path| path.extension().is_some_and(|ext| ext == "rlib"))
+    {
+        return read_artifact(ArtifactKind::Rlib, rlib);
+    }
+
+    match lib_files.as_slice() {
+        [] => {}
+        [library] => return read_artifact(ArtifactKind::Library, library),
+        many => {
+            let names: Vec<String> = many.iter().map(|path| file_name(path)).collect();
+            return Err(ambiguous(params, &names));
+        }
+    }
+
+    let bins: Vec<(&str, &PathBuf)> = units
+        .iter()
+        .filter_map(|unit| Some((unit.target.name.as_str(), unit.executable.as_ref()?)))
+        .collect();
+
+    // A package with several binaries is resolved to the one named after it.
+    let bin = match bins.as_slice() {
+        [] => None,
+        [(_, path)] => Some(*path),
+        many => match many.iter().find(|(name, _)| *name == params.crate_name) {
+            Some((_, path)) => Some(*path),
+            None => {
+                let names: Vec<&str> = many.iter().map(|(name, _)| *name).collect();
+                return Err(ambiguous(params, &names));
+            }
+        },
+    };
+    if let Some(path) = bin {
+        return read_artifact(ArtifactKind::Binary, path);
+    }
+
+    error!(crate_name = %params.crate_name, "No output file found");
+    Err(BuildError::Failed(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "No output file found".to_string(),
+    ))
+}
+
+fn read_artifact(kind: ArtifactKind, path: &Path) -> Result<Artifact, BuildError> {
+    match fs::read(path) {
+        Ok(data) => Ok(Artifact {
+            kind,
+            filename: file_name(path),
+            data: data.into(),
+        }),
+        Err(e) => {
+            error!(path = %path.display(), error = ?e, "Failed to read build output");
+            Err(BuildError::Failed(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to read build output".to_string(),
+            ))
+        }
+    }
+}
+
+fn ambiguous(params: &CompileParams, candidates: &[impl AsRef<str>]) -> BuildError {
+    let candidates: Vec<&str> = candidates.iter().map(AsRef::as_ref).collect();
+    error!(
+        crate_name = %params.crate_name,
+        candidates = %candidates.join(", "),
+        "Ambiguous build output"
+    );
+    BuildError::Failed(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        format!(
+            "Ambiguous build output for {}: {}",
+            params.crate_name,
+            candidates.join(", ")
+        ),
+    )
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Resolves once `cancel` flips to `true`.
+pub async fn cancelled(cancel: &mut watch::Receiver<bool>) {
+    let _ = cancel.wait_for(|cancelled| *cancelled).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn passes_only_toolchain_and_artifact_variables_to_cargo() {
+        let vars = [
+            ("PATH", "/usr/bin"),
+            ("HOME", "/home/worker"),
+            ("RUSTFLAGS", "-C target-cpu=native"),
+            ("CC", "clang"),
+            (
+                "CARGO_TARGET_AARCH64_UNKNOWN_LINUX_GNU_LINKER",
+                "aarch64-linux-gnu-gcc",
+            ),
+            ("CARGO_PROFILE_RELEASE_LTO", "true"),
+            ("CARGO_TARGET_DIR", "/elsewhere"),
+            ("WORKER_AUTH_TOKENS", "secret"),
+            ("AWS_SECRET_ACCESS_KEY", "secret"),
+        ]
+        .map(|(name, value)| (OsString::from(name), OsString::from(value)));
+        let params: CompileParams =
+            serde_json::from_value(serde_json::json!({ "crate_name": "app" })).unwrap();
+        let command = cargo_command(
+            Path::new("/work"),
+            Some(Path::new("/targets/key")),
+            &params,
+            vars,
+        );
+
+        let env: BTreeMap<_, _> = command
+            .as_std()
+            .get_envs()
+            .map(|(name, value)| {
+                (
+                    name.to_str().unwrap(),
+                    value.and_then(|value| value.to_str()).unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            env,
+            BTreeMap::from([
+                ("PATH", "/usr/bin"),
+                ("HOME", "/home/worker"),
+                ("RUSTFLAGS", "-C target-cpu=native"),
+                ("CC", "clang"),
+                (
+                    "CARGO_TARGET_AARCH64_UNKNOWN_LINUX_GNU_LINKER",
+                    "aarch64-linux-gnu-gcc"
+                ),
+                ("CARGO_PROFILE_RELEASE_LTO", "true"),
+                ("CARGO_TARGET_DIR", "/targets/key"),
+            ])
+        );
+    }
+}
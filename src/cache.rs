@@ -0,0 +1,334 @@
+//! Content-addressed cache of build results.
+//!
+//! Entries are keyed by a SHA-256 over the normalized source tree, the build
+//! parameters, the exact compiler version and the build-relevant environment, and
+//! stored as `<dir>/<key>/{meta.json,data}`.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    env,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+    time::SystemTime,
+};
+use tracing::warn;
+use walkdir::WalkDir;
+
+use crate::build::{Artifact, ArtifactKind, affects_artifacts};
+use crate::params::{CompileParams, Compression, OutputMode};
+use crate::util::hex;
+
+/// Bumped whenever the key derivation or the entry layout changes.
+const CACHE_VERSION: &str = "distbuild-cache-v1";
+
+#[derive(Serialize, Deserialize)]
+struct EntryMeta {
+    kind: ArtifactKind,
+    filename: String,
+}
+
+/// The parameter part of a key.
+#[derive(Serialize)]
+struct KeyParams {
+    cargo_args: Vec<String>,
+    output: OutputMode,
+    compression: Compression,
+}
+
+pub struct BuildCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    /// The build-relevant environment variables, sorted by name.
+    env: Vec<(String, String)>,
+    /// Hash of everything about the worker itself that goes into every key.
+    fingerprint: RwLock<String>,
+    /// Serializes eviction against concurrent inserts.
+    evict_lock: Mutex<()>,
+}
+
+impl BuildCache {
+    pub fn open(dir: PathBuf, max_bytes: u64, rustc_version: &str) -> io::Result<BuildCache> {
+        fs::create_dir_all(&dir)?;
+        let mut env: Vec<(String, String)> = env::vars()
+            .filter(|(name, _)| affects_artifacts(name))
+            .collect();
+        env.sort();
+        Ok(BuildCache {
+            dir,
+            max_bytes,
+            fingerprint: RwLock::new(fingerprint(rustc_version, &env)),
+            env,
+            evict_lock: Mutex::new(()),
+        })
+    }
+
+    /// Keys later lookups to `rustc_version`, after the toolchain was detected again.
+    /// Entries built by the previous compiler are no longer found.
+    pub fn set_rustc_version(&self, rustc_version: &str) {
+        *self.fingerprint.write().unwrap() = fingerprint(rustc_version, &self.env);
+    }
+
+    /// Computes the key for building `params` from the workspace unpacked at `workspace`.
+    ///
+    /// Only paths, executable bits, symlink targets and file contents are hashed, so
+    /// the same sources produce the same key regardless of timestamps or ownership.
+    /// A top-level `target/` directory is ignored.
+    pub fn key(&self, workspace: &Path, params: &CompileParams) -> io::Result<String> {
+        let mut hasher = Sha256::new();
+        hasher.update(&*self.fingerprint.read().unwrap());
+
+        let key_params = KeyParams {
+            cargo_args: params.cargo_args(),
+            output: params.output,
+            compression: params.compression,
+        };
+        hasher.update(serde_json::to_vec(&key_params)?);
+
+        let walker = WalkDir::new(workspace)
+            .min_depth(1)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|entry| !(entry.depth() == 1 && entry.file_name() == "target"));
+
+        for entry in walker {
+            let entry = entry.map_err(io::Error::other)?;
+            let path = entry.path().strip_prefix(workspace).unwrap_or(entry.path());
+            let path = path.to_string_lossy();
+            let file_type = entry.file_type();
+
+            if file_type.is_symlink() {
+                let target = fs::read_link(entry.path())?;
+                hasher.update(format!("\nL {}\0{}", path, target.to_string_lossy()));
+            } else if file_type.is_dir() {
+                hasher.update(format!("\nD {}", path));
+            } else if file_type.is_file() {
+                let executable = is_executable(&entry.metadata().map_err(io::Error::other)?);
+                let mut file = File::open(entry.path())?;
+                let mut contents = Sha256::new();
+                io::copy(&mut file, &mut contents)?;
+                hasher.update(format!(
+                    "\nF {}\0{}\0{}",
+                    path,
+                    executable,
+                    hex(&contents.finalize())
+                ));
+            }
+        }
+
+        Ok(hex(&hasher.finalize()))
+    }
+
+    pub fn get(&self, key: &str) -> Option<Artifact> {
+        let entry = self.dir.join(key);
+        let meta: EntryMeta =
+            serde_json::from_slice(&fs::read(entry.join("meta.json")).ok()?).ok()?;
+        let data = fs::read(entry.join("data")).ok()?;
+
+        // The meta file's mtime records the last use, for eviction.
+        if let Ok(file) = File::options().write(true).open(entry.join("meta.json")) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+
+        Some(Artifact {
+            kind: meta.kind,
+            filename: meta.filename,
+            data: data.into(),
+        })
+    }
+
+    pub fn put(&self, key: &str, artifact: &Artifact) {
+        if let Err(e) = self.write_entry(key, artifact) {
+            warn!(%key, error = ?e, "Failed to cache build result");
+            return;
+        }
+        self.evict();
+    }
+
+    fn write_entry(&self, key: &str, artifact: &Artifact) -> io::Result<()> {
+        // Written to a scratch directory first so readers never see a partial entry.
+        let staging = tempfile::Builder::new()
+            .prefix(".staging-")
+            .tempdir_in(&self.dir)?;
+        let meta = EntryMeta {
+            kind: artifact.kind,
+            filename: artifact.filename.clone(),
+        };
+        fs::write(staging.path().join("data"), &artifact.data)?;
+        fs::write(staging.path().join("meta.json"), serde_json::to_vec(&meta)?)?;
+
+        match fs::rename(staging.path(), self.dir.join(key)) {
+            Ok(()) => Ok(()),
+            // Another build of the same inputs got there first.
+            Err(_) if self.dir.join(key).join("meta.json").exists() => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Removes least recently used entries until the cache fits in `max_bytes`.
+    fn evict(&self) {
+        let _guard = self.evict_lock.lock().unwrap();
+
+        let Ok(dirs) = fs::read_dir(&self.dir) else {
+            return;
+        };
+        let mut entries: Vec<(SystemTime, u64, PathBuf)> = dirs
+            .flatten()
+            .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+            .filter_map(|entry| {
+                let path = entry.path();
+                let used = fs::metadata(path.join("meta.json")).ok()?.modified().ok()?;
+                let size = fs::metadata(path.join("data")).ok()?.len();
+                Some((used, size, path))
+            })
+            .collect();
+
+        let mut total: u64 = entries.iter().map(|(_, size, _)| size).sum();
+        entries.sort();
+        for (_, size, path) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            if fs::remove_dir_all(&path).is_ok() {
+                total -= size;
+            }
+        }
+    }
+}
+
+/// Hash of the cache version, `rustc_version` and the build-relevant environment.
+fn fingerprint(rustc_version: &str, env: &[(String, String)]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(CACHE_VERSION);
+    hasher.update(b"\0rustc\0");
+    hasher.update(rustc_version);
+    for (name, value) in env {
+        hasher.update(format!("\0env\0{}={}", name, value));
+    }
+    hex(&hasher.finalize())
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+    use tempfile::TempDir;
+
+    fn params(query: serde_json::Value) -> CompileParams {
+        let mut query = query;
+        query["crate_name"] = serde_json::json!("app");
+        serde_json::from_value(query).unwrap()
+    }
+
+    fn open_cache(tmp: &TempDir) -> BuildCache {
+        BuildCache::open(tmp.path().join("cache"), 0, "rustc 1.95.0").unwrap()
+    }
+
+    /// Writes `files` into a fresh workspace, in the given order.
+    fn workspace(tmp: &TempDir, name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = tmp.path().join(name);
+        for (path, contents) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        dir
+    }
+
+    const FILES: &[(&str, &str)] = &[
+        ("Cargo.toml", "[package]"),
+        ("src/lib.rs", "pub fn f() {}"),
+        ("src/main.rs", "fn main() {}"),
+    ];
+
+    #[test]
+    fn key_ignores_file_order_timestamps_and_target() {
+        let tmp = TempDir::new().unwrap();
+        let cache = open_cache(&tmp);
+        let params = params(serde_json::json!({}));
+
+        let first = workspace(&tmp, "first", FILES);
+        let mut reversed = FILES.to_vec();
+        reversed.reverse();
+        let second = workspace(&tmp, "second", &reversed);
+        File::options()
+            .write(true)
+            .open(second.join("src/lib.rs"))
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(1))
+            .unwrap();
+        fs::create_dir_all(second.join("target/debug")).unwrap();
+        fs::write(second.join("target/debug/libapp.rlib"), "stale").unwrap();
+
+        assert_eq!(
+            cache.key(&first, &params).unwrap(),
+            cache.key(&second, &params).unwrap()
+        );
+    }
+
+    #[test]
+    fn key_changes_with_sources() {
+        let tmp = TempDir::new().unwrap();
+        let cache = open_cache(&tmp);
+        let params = params(serde_json::json!({}));
+
+        let base = cache.key(&workspace(&tmp, "base", FILES), &params).unwrap();
+        let edited = workspace(&tmp, "edited", FILES);
+        fs::write(edited.join("src/lib.rs"), "pub fn g() {}").unwrap();
+        let renamed = workspace(&tmp, "renamed", FILES);
+        fs::rename(renamed.join("src/main.rs"), renamed.join("src/bin.rs")).unwrap();
+
+        assert_ne!(base, cache.key(&edited, &params).unwrap());
+        assert_ne!(base, cache.key(&renamed, &params).unwrap());
+    }
+
+    #[test]
+    fn key_changes_with_build_parameters() {
+        let tmp = TempDir::new().unwrap();
+        let cache = open_cache(&tmp);
+        let dir = workspace(&tmp, "ws", FILES);
+        let key = |query| cache.key(&dir, &params(query)).unwrap();
+
+        let base = key(serde_json::json!({}));
+        for query in [
+            serde_json::json!({ "profile": "release" }),
+            serde_json::json!({ "features": "serde" }),
+            serde_json::json!({ "target": "wasm32-unknown-unknown" }),
+            serde_json::json!({ "output": "archive" }),
+        ] {
+            assert_ne!(base, key(query.clone()), "{query}");
+        }
+    }
+
+    #[test]
+    fn key_changes_with_compiler_and_environment() {
+        let tmp = TempDir::new().unwrap();
+        let dir = workspace(&tmp, "ws", FILES);
+        let params = params(serde_json::json!({}));
+        let cache = open_cache(&tmp);
+        let base = cache.key(&dir, &params).unwrap();
+
+        cache.set_rustc_version("rustc 1.96.0");
+        assert_ne!(base, cache.key(&dir, &params).unwrap());
+        cache.set_rustc_version("rustc 1.95.0");
+        assert_eq!(base, cache.key(&dir, &params).unwrap());
+
+        let mut flagged = open_cache(&tmp);
+        flagged.env = vec![("RUSTFLAGS".to_string(), "-C target-cpu=native".to_string())];
+        flagged.set_rustc_version("rustc 1.95.0");
+        assert_ne!(base, flagged.key(&dir, &params).unwrap());
+    }
+}
@@ -0,0 +1,191 @@
+//! What this worker can build and on what hardware, for coordinators deciding where
+//! to send a build.
+
+use serde::Serialize;
+use std::{
+    ffi::CString,
+    fs, io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::process::Command;
+use tracing::warn;
+
+use crate::coordinator::PROTOCOL_VERSION;
+
+#[derive(Clone, Serialize)]
+pub struct Capabilities {
+    pub toolchains: Vec<InstalledToolchain>,
+    pub hardware: Hardware,
+    pub protocol_versions: Vec<u32>,
+    pub features: Features,
+    /// Unix time the toolchains and hardware were last probed.
+    pub collected_at: u64,
+}
+
+#[derive(Clone, Serialize)]
+pub struct InstalledToolchain {
+    /// Rustup toolchain name, or `system` without rustup.
+    pub name: String,
+    pub default: bool,
+    pub rustc_version: String,
+    pub cargo_version: String,
+    pub targets: Vec<String>,
+    pub components: Vec<String>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct Hardware {
+    pub cpus: usize,
+    pub memory_total_bytes: u64,
+    pub memory_available_bytes: u64,
+    /// Space in the work directory.
+    pub disk_total_bytes: u64,
+    pub disk_free_bytes: u64,
+}
+
+/// Optional parts of the worker and whether this instance has them enabled.
+#[derive(Clone, Serialize)]
+pub struct Features {
+    pub sandbox: bool,
+    pub seccomp: bool,
+    pub cache: bool,
+    pub persistent_workspaces: bool,
+    pub delta_uploads: bool,
+    pub upload_compression: Vec<&'static str>,
+    pub artifact_compression: Vec<&'static str>,
+}
+
+impl Capabilities {
+    /// Probes the installed toolchains and the hardware.
+    pub async fn collect(work_dir: PathBuf, features: Features) -> Capabilities {
+        let toolchains = installed_toolchains().await;
+        let hardware = tokio::task::spawn_blocking(move || Hardware::probe(&work_dir))
+            .await
+            .expect("hardware probe panicked");
+        Capabilities {
+            toolchains,
+            hardware,
+            protocol_versions: vec![PROTOCOL_VERSION],
+            features,
+            collected_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or(0),
+        }
+    }
+}
+
+impl Hardware {
+    fn probe(work_dir: &Path) -> Hardware {
+        let meminfo = fs::read_to_string("/proc/meminfo").unwrap_or_default();
+        let (disk_total_bytes, disk_free_bytes) = disk_space(work_dir)
+            .inspect_err(
+                |e| warn!(dir = %work_dir.display(), error = %e, "Cannot stat work directory"),
+            )
+            .unwrap_or((0, 0));
+        Hardware {
+            cpus: std::thread::available_parallelism().map_or(1, usize::from),
+            memory_total_bytes: meminfo_bytes(&meminfo, "MemTotal"),
+            memory_available_bytes: meminfo_bytes(&meminfo, "MemAvailable"),
+            disk_total_bytes,
+            disk_free_bytes,
+        }
+    }
+}
+
+/// Total and available bytes on the filesystem holding `path`.
+pub fn disk_space(path: &Path) -> io::Result<(u64, u64)> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: `path` is NUL-terminated and `stat` is a plain out-parameter.
+    let stat = unsafe {
+        let mut stat = std::mem::zeroed::<libc::statvfs>();
+        if libc::statvfs(path.as_ptr(), &mut stat) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat
+    };
+    let block = stat.f_frsize as u64;
+    Ok((stat.f_blocks as u64 * block, stat.f_bavail as u64 * block))
+}
+
+/// Reads a `kB` figure such as `MemTotal:  16318412 kB` from `/proc/meminfo`.
+fn meminfo_bytes(meminfo: &str, key: &str) -> u64 {
+    meminfo
+        .lines()
+        .find_map(|line| {
+            let value = line.strip_prefix(key)?.strip_prefix(':')?;
+            value
+                .trim()
+                .trim_end_matches("kB")
+                .trim()
+                .parse::<u64>()
+                .ok()
+        })
+        .map_or(0, |kib| kib * 1024)
+}
+
+async fn installed_toolchains() -> Vec<InstalledToolchain> {
+    let Some(list) = output("rustup", &["toolchain", "list"]).await else {
+        // Without rustup only the toolchain on PATH is available.
+        return vec![InstalledToolchain {
+            name: "system".to_string(),
+            default: true,
+            rustc_version: output("rustc", &["-vV"]).await.unwrap_or_default(),
+            cargo_version: output("cargo", &["-V"]).await.unwrap_or_default(),
+            targets: Vec::new(),
+            components: Vec::new(),
+        }];
+    };
+
+    let mut toolchains = Vec::new();
+    // Lines look like `stable-x86_64-unknown-linux-gnu (active, default)`.
+    for line in list.lines().filter(|line| !line.trim().is_empty()) {
+        let (name, flags) = line.split_once(' ').unwrap_or((line, ""));
+        let name = name.trim();
+        toolchains.push(InstalledToolchain {
+            name: name.to_string(),
+            default: flags.contains("default"),
+            rustc_version: output("rustup", &["run", name, "rustc", "-vV"])
+                .await
+                .unwrap_or_default(),
+            cargo_version: output("rustup", &["run", name, "cargo", "-V"])
+                .await
+                .map(|version| version.trim().to_string())
+                .unwrap_or_default(),
+            targets: lines(
+                output(
+                    "rustup",
+                    &["target", "list", "--installed", "--toolchain", name],
+                )
+                .await,
+            ),
+            components: lines(
+                output(
+                    "rustup",
+                    &["component", "list", "--installed", "--toolchain", name],
+                )
+                .await,
+            ),
+        });
+    }
+    toolchains
+}
+
+async fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().await.ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn lines(text: Option<String>) -> Vec<String> {
+    text.unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
@@ -0,0 +1,168 @@
+//! Worker settings, read from the environment at startup.
+
+use std::{
+    env,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::extract::ExtractLimits;
+use crate::limits::ResourceLimits;
+use crate::sandbox::SandboxMode;
+
+const MIB: u64 = 1024 * 1024;
+const GIB: u64 = 1024 * MIB;
+
+pub struct Config {
+    pub port: u16,
+    /// Address the HTTP listener binds to.
+    pub bind_address: IpAddr,
+    /// Identifies this worker to the coordinator; random unless configured.
+    pub worker_id: String,
+    /// Coordinator to register with; `None` runs the worker standalone.
+    pub coordinator_url: Option<String>,
+    /// Base URL the coordinator should use to reach this worker.
+    pub advertise_url: String,
+    pub heartbeat_interval_secs: u64,
+    /// Root for everything the worker writes: unpacked uploads, cache, workspaces.
+    pub work_dir: PathBuf,
+    /// Where build results are cached; `None` disables the cache.
+    pub cache_dir: Option<PathBuf>,
+    pub cache_max_bytes: u64,
+    /// Total size of persistent workspaces before the least recently used are
+    /// evicted; `0` disables them.
+    pub workspaces_max_bytes: u64,
+    /// Size of the delta upload blob store; `0` disables delta uploads.
+    pub blobs_max_bytes: u64,
+    pub extract_limits: ExtractLimits,
+    /// Largest request body accepted, compressed; larger uploads get `413`.
+    pub max_body_bytes: u64,
+    /// Largest delta upload manifest accepted; manifests are parsed in memory.
+    pub max_manifest_bytes: u64,
+    /// Number of cargo builds allowed to run at once.
+    pub executor_slots: usize,
+    /// Number of builds allowed to wait for a slot before new ones get `503`.
+    pub queue_capacity: usize,
+    /// Free space in the work directory below which the worker reports not ready.
+    pub min_free_disk_bytes: u64,
+    /// How long shutdown waits for queued and running builds.
+    pub drain_timeout_secs: u64,
+    /// Bearer tokens clients may authenticate with, one per line.
+    pub auth_tokens_file: Option<PathBuf>,
+    /// HMAC signing keys, as `<key id> <secret>` lines.
+    pub auth_hmac_keys_file: Option<PathBuf>,
+    /// How far a signed request's timestamp may be from the worker's clock.
+    pub auth_max_skew_secs: u64,
+    /// Build timeout when a request does not ask for one.
+    pub build_timeout_secs: u64,
+    /// Upper bound on the timeout a request can ask for.
+    pub max_build_timeout_secs: u64,
+    /// Whether builds run in a namespace sandbox; `none` where unprivileged user
+    /// namespaces are unavailable.
+    pub sandbox: SandboxMode,
+    /// Adds a seccomp filter to the sandbox.
+    pub sandbox_seccomp: bool,
+    pub job_limits: ResourceLimits,
+}
+
+impl Config {
+    pub fn from_env() -> Config {
+        let work_dir = env::var_os("WORKER_WORK_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| env::temp_dir().join("distbuild-worker"));
+
+        let cache_max_bytes = env_or("WORKER_CACHE_MAX_BYTES", 10 * GIB);
+        let cache_dir = (cache_max_bytes > 0).then(|| {
+            env::var_os("WORKER_CACHE_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| work_dir.join("cache"))
+        });
+
+        let port = env_or("PORT", 5000);
+        let bind_address = env_or("WORKER_BIND_ADDRESS", IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        Config {
+            port,
+            bind_address,
+            worker_id: env::var("WORKER_ID").unwrap_or_else(|_| Uuid::new_v4().to_string()),
+            coordinator_url: env::var("WORKER_COORDINATOR_URL")
+                .ok()
+                .map(|url| url.trim_end_matches('/').to_string())
+                .filter(|url| !url.is_empty()),
+            advertise_url: env::var("WORKER_ADVERTISE_URL")
+                .unwrap_or_else(|_| format!("http://{}", SocketAddr::new(bind_address, port))),
+            heartbeat_interval_secs: env_or("WORKER_HEARTBEAT_SECS", 10).max(1),
+            cache_dir,
+            cache_max_bytes,
+            workspaces_max_bytes: env_or("WORKER_WORKSPACES_MAX_BYTES", 20 * GIB),
+            blobs_max_bytes: env_or("WORKER_BLOBS_MAX_BYTES", 10 * GIB),
+            max_body_bytes: env_or("WORKER_MAX_BODY_BYTES", 2 * GIB),
+            max_manifest_bytes: env_or("WORKER_MAX_MANIFEST_BYTES", 8 * MIB),
+            executor_slots: env_or("WORKER_EXECUTOR_SLOTS", 2),
+            queue_capacity: env_or("WORKER_QUEUE_CAPACITY", 32),
+            min_free_disk_bytes: env_or("WORKER_MIN_FREE_DISK_BYTES", 1024 * 1024 * 1024),
+            drain_timeout_secs: env_or("WORKER_DRAIN_TIMEOUT_SECS", 60),
+            auth_tokens_file: env::var_os("WORKER_AUTH_TOKENS_FILE").map(PathBuf::from),
+            auth_hmac_keys_file: env::var_os("WORKER_AUTH_HMAC_KEYS_FILE").map(PathBuf::from),
+            auth_max_skew_secs: env_or("WORKER_AUTH_MAX_SKEW_SECS", 300),
+            build_timeout_secs: env_or("WORKER_BUILD_TIMEOUT_SECS", 600),
+            max_build_timeout_secs: env_or("WORKER_MAX_BUILD_TIMEOUT_SECS", 3600),
+            sandbox: env_or("WORKER_SANDBOX", SandboxMode::Namespaces),
+            sandbox_seccomp: env_or("WORKER_SANDBOX_SECCOMP", false),
+            job_limits: ResourceLimits {
+                cpus: env_or("WORKER_JOB_CPUS", 0.0),
+                memory_bytes: env_or("WORKER_JOB_MEMORY_BYTES", 0),
+                pids: env_or("WORKER_JOB_PIDS", 0),
+                file_bytes: env_or("WORKER_JOB_FILE_BYTES", 0),
+                disk_bytes: env_or("WORKER_JOB_DISK_BYTES", 0),
+            },
+            extract_limits: ExtractLimits {
+                max_total_bytes: env_or("WORKER_UNPACK_MAX_BYTES", 8 * GIB),
+                max_entries: env_or("WORKER_UNPACK_MAX_ENTRIES", 500_000),
+                max_file_bytes: env_or("WORKER_UNPACK_MAX_FILE_BYTES", GIB),
+            },
+            work_dir,
+        }
+    }
+
+    /// The timeout for a build that asked for `requested` seconds.
+    pub fn build_timeout(&self, requested: Option<u64>) -> Duration {
+        let secs = requested
+            .unwrap_or(self.build_timeout_secs)
+            .min(self.max_build_timeout_secs);
+        Duration::from_secs(secs)
+    }
+
+    /// Scratch directories for uploads being unpacked and built.
+    pub fn jobs_dir(&self) -> PathBuf {
+        self.work_dir.join("jobs")
+    }
+
+    pub fn workspaces_dir(&self) -> PathBuf {
+        self.work_dir.join("workspaces")
+    }
+
+    pub fn blobs_dir(&self) -> PathBuf {
+        self.work_dir.join("blobs")
+    }
+
+    /// Mount point for the sandbox's root filesystem; always empty on the host.
+    pub fn sandbox_root(&self) -> PathBuf {
+        self.work_dir.join("sandbox-root")
+    }
+}
+
+/// Parses `name` from the environment, falling back to `default` when unset or invalid.
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            warn!(name, ?value, "Ignoring invalid setting");
+            default
+        }),
+        Err(_) => default,
+    }
+}
@@ -0,0 +1,166 @@
+//! Registration with a coordinator, which discovers workers and routes builds to them.
+//!
+//! The protocol is plain JSON over HTTP, initiated by the worker; see the README for
+//! the message formats. The worker registers at startup, sends a heartbeat every
+//! `WORKER_HEARTBEAT_SECS`, registers again if the coordinator no longer knows it or
+//! its toolchain changed, and deregisters on graceful shutdown.
+
+use reqwest::StatusCode;
+use serde::Serialize;
+use std::{sync::Arc, time::Duration};
+use tracing::{info, warn};
+
+use crate::AppState;
+use crate::config::Config;
+use crate::queue::QueueLoad;
+use crate::toolchain::Toolchain;
+
+/// Version of the worker/coordinator protocol described in the README.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// How long deregistration may delay shutdown.
+const DEREGISTER_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Coordinator {
+    client: reqwest::Client,
+    url: String,
+    worker_id: String,
+    advertise_url: String,
+    interval: Duration,
+}
+
+#[derive(Serialize)]
+struct Registration<'a> {
+    protocol_version: u32,
+    worker_id: &'a str,
+    address: &'a str,
+    capacity: Capacity,
+    toolchains: [&'a Toolchain; 1],
+    targets: &'a [String],
+}
+
+#[derive(Serialize)]
+struct Capacity {
+    executor_slots: usize,
+    queue_capacity: usize,
+}
+
+#[derive(Serialize)]
+struct Heartbeat<'a> {
+    worker_id: &'a str,
+    #[serde(flatten)]
+    load: QueueLoad,
+}
+
+impl Coordinator {
+    pub fn from_config(config: &Config) -> Option<Coordinator> {
+        let url = config.coordinator_url.clone()?;
+        let interval = Duration::from_secs(config.heartbeat_interval_secs);
+        let client = reqwest::Client::builder()
+            .timeout(interval.max(Duration::from_secs(5)))
+            .build()
+            .expect("Failed to build HTTP client");
+        Some(Coordinator {
+            client,
+            url,
+            worker_id: config.worker_id.clone(),
+            advertise_url: config.advertise_url.clone(),
+            interval,
+        })
+    }
+
+    /// Registers, then keeps sending heartbeats for as long as the worker runs.
+    pub async fn run(self: Arc<Self>, state: Arc<AppState>) {
+        let mut ticker = tokio::time::interval(self.interval);
+        // The toolchain the coordinator was last told about.
+        let mut registered: Option<Toolchain> = None;
+        loop {
+            ticker.tick().await;
+            let toolchain = state.toolchain.read().unwrap().clone();
+            registered = match registered {
+                Some(sent) if sent == toolchain => self.heartbeat(&state).await.then_some(sent),
+                _ => self.register(&state, &toolchain).await.then_some(toolchain),
+            };
+        }
+    }
+
+    /// Tells the coordinator to stop routing builds here.
+    pub async fn deregister(&self) {
+        let url = format!("{}/workers/{}", self.url, self.worker_id);
+        let request = self.client.delete(&url).send();
+        match tokio::time::timeout(DEREGISTER_TIMEOUT, request).await {
+            Ok(Ok(response)) if response.status().is_success() => {
+                info!(coordinator = %self.url, "Deregistered from coordinator");
+            }
+            Ok(Ok(response)) => {
+                warn!(status = %response.status(), "Coordinator refused deregistration");
+            }
+            Ok(Err(e)) => warn!(error = %e, "Failed to deregister from coordinator"),
+            Err(_) => warn!("Coordinator did not answer deregistration in time"),
+        }
+    }
+
+    /// Returns whether the worker is now registered.
+    async fn register(&self, state: &AppState, toolchain: &Toolchain) -> bool {
+        let load = state.jobs.load();
+        let registration = Registration {
+            protocol_version: PROTOCOL_VERSION,
+            worker_id: &self.worker_id,
+            address: &self.advertise_url,
+            capacity: Capacity {
+                executor_slots: load.executor_slots,
+                queue_capacity: load.queue_capacity,
+            },
+            toolchains: [toolchain],
+            targets: &toolchain.targets,
+        };
+
+        let url = format!("{}/workers/register", self.url);
+        match self.client.post(&url).json(&registration).send().await {
+            Ok(response) if response.status().is_success() => {
+                info!(
+                    coordinator = %self.url,
+                    worker_id = %self.worker_id,
+                    "Registered with coordinator"
+                );
+                true
+            }
+            Ok(response) => {
+                warn!(status = %response.status(), "Coordinator refused registration");
+                false
+            }
+            Err(e) => {
+                warn!(error = %e, "Failed to register with coordinator");
+                false
+            }
+        }
+    }
+
+    /// Reports the current load. Returns `false` if the worker must register again.
+    async fn heartbeat(&self, state: &AppState) -> bool {
+        let heartbeat = Heartbeat {
+            worker_id: &self.worker_id,
+            load: state.jobs.load(),
+        };
+
+        let url = format!("{}/workers/{}/heartbeat", self.url, self.worker_id);
+        match self.client.post(&url).json(&heartbeat).send().await {
+            Ok(response) if response.status().is_success() => true,
+            Ok(response)
+                if matches!(response.status(), StatusCode::NOT_FOUND | StatusCode::GONE) =>
+            {
+                info!("Coordinator forgot this worker, registering again");
+                false
+            }
+            Ok(response) => {
+                warn!(status = %response.status(), "Coordinator rejected heartbeat");
+                true
+            }
+            // A missed heartbeat is retried on the next tick.
+            Err(e) => {
+                warn!(error = %e, "Failed to send heartbeat");
+                true
+            }
+        }
+    }
+}
@@ -0,0 +1,709 @@
+//! Unpacking uploaded workspace tarballs.
+//!
+//! Uploads are untrusted, so entries are unpacked one by one instead of through
+//! `Archive::unpack`. Every entry must stay inside the destination, links may not
+//! point outside it, only regular files, directories and links are created, and
+//! the archive's size and entry count are capped.
+
+use axum::http::{HeaderMap, header};
+use flate2::read::GzDecoder;
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+use tar::{Archive, EntryType};
+use xz2::read::XzDecoder;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UploadEncoding {
+    Identity,
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl UploadEncoding {
+    /// Determines how an upload is compressed.
+    ///
+    /// An explicit `Content-Encoding` wins, then a compression `Content-Type`, and
+    /// otherwise the body's magic bytes decide. Encodings the worker cannot decode
+    /// are returned as an error naming them.
+    pub fn detect(headers: &HeaderMap, body: &[u8]) -> Result<UploadEncoding, String> {
+        if let Some(encoding) = header_value(headers, header::CONTENT_ENCODING) {
+            return match encoding.as_str() {
+                "identity" => Ok(UploadEncoding::Identity),
+                "gzip" | "x-gzip" => Ok(UploadEncoding::Gzip),
+                "zstd" => Ok(UploadEncoding::Zstd),
+                "xz" | "x-xz" => Ok(UploadEncoding::Xz),
+                other => Err(format!("Unsupported Content-Encoding: {}", other)),
+            };
+        }
+
+        let from_type =
+            header_value(headers, header::CONTENT_TYPE).and_then(|content_type| match content_type
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+            {
+                "application/gzip" | "application/x-gzip" => Some(UploadEncoding::Gzip),
+                "application/zstd" => Some(UploadEncoding::Zstd),
+                "application/x-xz" => Some(UploadEncoding::Xz),
+                _ => None,
+            });
+        if let Some(encoding) = from_type {
+            return Ok(encoding);
+        }
+
+        Ok(if body.starts_with(GZIP_MAGIC) {
+            UploadEncoding::Gzip
+        } else if body.starts_with(ZSTD_MAGIC) {
+            UploadEncoding::Zstd
+        } else if body.starts_with(XZ_MAGIC) {
+            UploadEncoding::Xz
+        } else {
+            UploadEncoding::Identity
+        })
+    }
+
+    /// Wraps `reader` in the matching decompressor.
+    pub fn decoder<'a>(self, reader: impl Read + 'a) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            UploadEncoding::Identity => Box::new(reader),
+            UploadEncoding::Gzip => Box::new(GzDecoder::new(reader)),
+            UploadEncoding::Zstd => Box::new(zstd::Decoder::new(reader)?),
+            UploadEncoding::Xz => Box::new(XzDecoder::new(reader)),
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct ExtractLimits {
+    /// Sum of all entry sizes, after decompression.
+    pub max_total_bytes: u64,
+    pub max_entries: u64,
+    pub max_file_bytes: u64,
+}
+
+pub enum ExtractError {
+    /// The archive violates a safety rule or limit; `entry` names the offender.
+    Rejected {
+        entry: String,
+        reason: String,
+    },
+    Io(io::Error),
+}
+
+impl From<io::Error> for ExtractError {
+    fn from(e: io::Error) -> ExtractError {
+        ExtractError::Io(e)
+    }
+}
+
+/// Decompresses and unpacks a tarball into `dest` in a single streaming pass.
+pub fn unpack(
+    body: impl Read,
+    encoding: UploadEncoding,
+    dest: &Path,
+    limits: ExtractLimits,
+) -> Result<(), ExtractError> {
+    let mut archive = Archive::new(encoding.decoder(body)?);
+    let mut entries_seen = 0u64;
+    let mut total_bytes = 0u64;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let raw_path = entry.path()?.to_string_lossy().into_owned();
+        let reject = |reason: &str| ExtractError::Rejected {
+            entry: raw_path.clone(),
+            reason: reason.to_string(),
+        };
+
+        let entry_type = entry.header().entry_type();
+        if entry_type == EntryType::XGlobalHeader {
+            continue;
+        }
+        // The archive root itself, as written by `tar -C dir .`.
+        if entry_type == EntryType::Directory
+            && Path::new(&raw_path)
+                .components()
+                .all(|c| c == Component::CurDir)
+        {
+            continue;
+        }
+
+        entries_seen += 1;
+        if entries_seen > limits.max_entries {
+            return Err(reject(&format!(
+                "archive has more than {} entries",
+                limits.max_entries
+            )));
+        }
+
+        let size = entry.size();
+        if size > limits.max_file_bytes {
+            return Err(reject(&format!(
+                "file is larger than {} bytes",
+                limits.max_file_bytes
+            )));
+        }
+        total_bytes += size;
+        if total_bytes > limits.max_total_bytes {
+            return Err(reject(&format!(
+                "archive unpacks to more than {} bytes",
+                limits.max_total_bytes
+            )));
+        }
+
+        let mode = entry.header().mode()?;
+        if mode & 0o6000 != 0 {
+            return Err(reject("setuid and setgid bits are not allowed"));
+        }
+
+        let Some(relative) = safe_relative_path(&raw_path) else {
+            return Err(reject("absolute paths and `..` components are not allowed"));
+        };
+        let path = prepare_path(dest, &relative).map_err(|reason| reject(&reason))?;
+
+        match entry_type {
+            EntryType::Directory => {
+                fs::create_dir_all(&path)?;
+            }
+            EntryType::Regular | EntryType::Continuous => {
+                let mut file = File::create(&path)?;
+                io::copy(&mut entry, &mut file)?;
+                if let Some(mtime) = entry.header().mtime().ok().filter(|m| *m > 0) {
+                    file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))?;
+                }
+                set_mode(&path, mode)?;
+            }
+            EntryType::Symlink => {
+                let target = link_target(&entry).map_err(|reason| reject(&reason))?;
+                check_link_target(dest, &relative, &target).map_err(|reason| reject(&reason))?;
+                symlink(&target, &path)?;
+            }
+            EntryType::Link => {
+                let target = link_target(&entry).map_err(|reason| reject(&reason))?;
+                let Some(target) = safe_relative_path(&target) else {
+                    return Err(reject("hard link points outside the workspace"));
+                };
+                let target = prepare_existing(dest, &target).map_err(|reason| reject(&reason))?;
+                fs::hard_link(target, &path)?;
+            }
+            EntryType::Char | EntryType::Block => {
+                return Err(reject("device nodes are not allowed"));
+            }
+            EntryType::Fifo => return Err(reject("FIFOs are not allowed")),
+            _ => return Err(reject("unsupported entry type")),
+        }
+    }
+
+    Ok(())
+}
+
+/// Converts an archive or manifest path into a relative path that cannot leave the
+/// workspace.
+pub fn safe_relative_path(path: &str) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    (!normalized.as_os_str().is_empty()).then_some(normalized)
+}
+
+/// Whether a symlink at `link` (relative to the root) pointing to `target` stays
+/// inside the root, judged from the paths alone.
+///
+/// Targets must be relative, and `..` may only lead: `../a/b` but not `a/../b`. A
+/// `..` after a component that is, or later becomes, a symlink steps back from the
+/// symlink's target rather than from its location, which the path text cannot
+/// show. Leading `..` steps are taken from the link's own directory, which
+/// [`prepare_path`] guarantees is a real directory, and every later step descends,
+/// either into a real directory or through a symlink held to the same rule.
+pub fn link_stays_inside(link: &Path, target: &str) -> bool {
+    let target = Path::new(target);
+    if target.is_absolute() {
+        return false;
+    }
+
+    let mut depth = link.components().count() as isize - 1;
+    let mut descended = false;
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => descended = true,
+            Component::CurDir => {}
+            Component::ParentDir => {
+                depth -= 1;
+                if descended || depth < 0 {
+                    return false;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
+/// Checks a symlink about to be created at `dest/link` against the tree unpacked so
+/// far: besides [`link_stays_inside`], its target may not pass through a symlink
+/// that already exists, and must resolve to a path inside `dest`.
+pub fn check_link_target(dest: &Path, link: &Path, target: &str) -> Result<(), String> {
+    if !link_stays_inside(link, target) {
+        return Err("symlink points outside the workspace".to_string());
+    }
+
+    let mut current = dest.join(link);
+    current.pop();
+    let mut components = Path::new(target).components().peekable();
+    while let Some(component) = components.next() {
+        match component {
+            Component::ParentDir => {
+                current.pop();
+            }
+            Component::Normal(part) => {
+                current.push(part);
+                let is_symlink =
+                    fs::symlink_metadata(&current).is_ok_and(|meta| meta.file_type().is_symlink());
+                if is_symlink && components.peek().is_some() {
+                    return Err("symlink target goes through a symlink".to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+    if !current.starts_with(dest) {
+        return Err("symlink points outside the workspace".to_string());
+    }
+    Ok(())
+}
+
+/// Readies `dest/relative` for a new entry: its parents are created, none of them
+/// may be a symlink, and a previous non-directory entry at the same path is removed
+/// so the write cannot follow it.
+pub fn prepare_path(dest: &Path, relative: &Path) -> Result<PathBuf, String> {
+    let mut current = dest.to_path_buf();
+    let mut components = relative.components().peekable();
+
+    while let Some(component) = components.next() {
+        current.push(component);
+        let existing = fs::symlink_metadata(&current).ok();
+
+        if components.peek().is_none() {
+            if let Some(meta) = existing
+                && !meta.is_dir()
+            {
+                fs::remove_file(&current).map_err(|e| e.to_string())?;
+            }
+            break;
+        }
+
+        match existing {
+            Some(meta) if meta.file_type().is_symlink() => {
+                return Err("path goes through a symlink".to_string());
+            }
+            Some(meta) if meta.is_dir() => {}
+            Some(_) => return Err("parent is not a directory".to_string()),
+            None => fs::create_dir(&current).map_err(|e| e.to_string())?,
+        }
+    }
+
+    Ok(current)
+}
+
+/// Resolves the target of a hard link, which must be a regular file already unpacked.
+fn prepare_existing(dest: &Path, relative: &Path) -> Result<PathBuf, String> {
+    let mut current = dest.to_path_buf();
+    for component in relative.components() {
+        current.push(component);
+        let meta = fs::symlink_metadata(&current).map_err(|_| "link target does not exist")?;
+        if meta.file_type().is_symlink() {
+            return Err("link target goes through a symlink".to_string());
+        }
+    }
+    if !current.is_file() {
+        return Err("link target is not a regular file".to_string());
+    }
+    Ok(current)
+}
+
+fn link_target<R: Read>(entry: &tar::Entry<R>) -> Result<String, String> {
+    match entry.link_name() {
+        Ok(Some(target)) => Ok(target.to_string_lossy().into_owned()),
+        _ => Err("link has no target".to_string()),
+    }
+}
+
+#[cfg(unix)]
+pub fn symlink(target: &str, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(not(unix))]
+pub fn symlink(_target: &str, _link: &Path) -> io::Result<()> {
+    Err(io::Error::other(
+        "symlinks are not supported on this platform",
+    ))
+}
+
+/// Applies only the executable bits of `mode`, so uploads cannot create
+/// world-writable or otherwise unusual files.
+#[cfg(unix)]
+pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = if mode & 0o111 != 0 { 0o755 } else { 0o644 };
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+pub fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+fn header_value(headers: &HeaderMap, name: header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tar::Header;
+    use tempfile::TempDir;
+
+    const LIMITS: ExtractLimits = ExtractLimits {
+        max_total_bytes: 1024,
+        max_entries: 16,
+        max_file_bytes: 512,
+    };
+
+    /// One archive entry, written with raw header fields so that paths `tar::Builder`
+    /// would refuse can still be produced.
+    struct Entry {
+        path: &'static str,
+        kind: EntryType,
+        link: &'static str,
+        data: &'static [u8],
+        mode: u32,
+    }
+
+    fn file(path: &'static str, data: &'static [u8]) -> Entry {
+        Entry {
+            path,
+            kind: EntryType::Regular,
+            link: "",
+            data,
+            mode: 0o644,
+        }
+    }
+
+    fn dir(path: &'static str) -> Entry {
+        Entry {
+            kind: EntryType::Directory,
+            mode: 0o755,
+            ..file(path, b"")
+        }
+    }
+
+    fn link(kind: EntryType, path: &'static str, link: &'static str) -> Entry {
+        Entry {
+            kind,
+            link,
+            mode: 0o777,
+            ..file(path, b"")
+        }
+    }
+
+    fn archive(entries: &[Entry]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for entry in entries {
+            let mut header = Header::new_old();
+            header.as_old_mut().name[..entry.path.len()].copy_from_slice(entry.path.as_bytes());
+            header.as_old_mut().linkname[..entry.link.len()].copy_from_slice(entry.link.as_bytes());
+            header.set_entry_type(entry.kind);
+            header.set_size(entry.data.len() as u64);
+            header.set_mode(entry.mode);
+            header.set_cksum();
+            builder.append(&header, entry.data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn unpack_into(dest: &TempDir, entries: &[Entry], limits: ExtractLimits) -> Result<(), String> {
+        let dest = dest.path().join("ws");
+        fs::create_dir(&dest).unwrap();
+        match unpack(
+            archive(entries).as_slice(),
+            UploadEncoding::Identity,
+            &dest,
+            limits,
+        ) {
+            Ok(()) => Ok(()),
+            Err(ExtractError::Rejected { entry, reason }) => Err(format!("{entry}: {reason}")),
+            Err(ExtractError::Io(e)) => panic!("unexpected I/O error: {e}"),
+        }
+    }
+
+    fn assert_rejected(entries: &[Entry], limits: ExtractLimits, expected: &str) {
+        let tmp = TempDir::new().unwrap();
+        let reason = unpack_into(&tmp, entries, limits).expect_err("archive was accepted");
+        assert!(reason.contains(expected), "{reason:?} lacks {expected:?}");
+        assert_eq!(
+            fs::read_dir(tmp.path()).unwrap().count(),
+            1,
+            "something was written outside the destination"
+        );
+    }
+
+    #[test]
+    fn unpacks_files_directories_and_links() {
+        let tmp = TempDir::new().unwrap();
+        unpack_into(
+            &tmp,
+            &[
+                dir("src"),
+                file("src/main.rs", b"fn main() {}"),
+                link(EntryType::Symlink, "src/lib.rs", "main.rs"),
+                link(EntryType::Symlink, "src/up", "../Cargo.toml"),
+                link(EntryType::Link, "copy.rs", "src/main.rs"),
+                file("Cargo.toml", b"[package]"),
+            ],
+            LIMITS,
+        )
+        .unwrap();
+
+        let ws = tmp.path().join("ws");
+        assert_eq!(fs::read(ws.join("src/lib.rs")).unwrap(), b"fn main() {}");
+        assert_eq!(fs::read(ws.join("src/up")).unwrap(), b"[package]");
+        assert_eq!(fs::read(ws.join("copy.rs")).unwrap(), b"fn main() {}");
+    }
+
+    #[test]
+    fn rejects_path_traversal() {
+        assert_rejected(&[file("../escape", b"x")], LIMITS, "");
+        assert_rejected(&[dir("a"), file("a/../../escape", b"x")], LIMITS, "");
+    }
+
+    #[test]
+    fn rejects_absolute_paths() {
+        assert_rejected(&[file("/tmp/escape", b"x")], LIMITS, "");
+        assert_rejected(
+            &[link(EntryType::Symlink, "l", "/etc")],
+            LIMITS,
+            "outside the workspace",
+        );
+    }
+
+    #[test]
+    fn rejects_symlinks_leaving_the_root() {
+        assert_rejected(
+            &[dir("a"), link(EntryType::Symlink, "a/l", "../..")],
+            LIMITS,
+            "outside the workspace",
+        );
+    }
+
+    #[test]
+    fn rejects_chained_symlinks() {
+        // `e` resolves to `./l/l/..`, which is the parent of the root.
+        assert_rejected(
+            &[
+                link(EntryType::Symlink, "l", "."),
+                link(EntryType::Symlink, "e", "l/l/.."),
+            ],
+            LIMITS,
+            "",
+        );
+        // Created in the other order, the link through `a` is only harmful once `a`
+        // becomes a symlink.
+        assert_rejected(
+            &[
+                dir("x"),
+                dir("y"),
+                link(EntryType::Symlink, "e", "x/a/../.."),
+                link(EntryType::Symlink, "x/a", "../y"),
+            ],
+            LIMITS,
+            "",
+        );
+        assert_rejected(
+            &[
+                link(EntryType::Symlink, "l", "."),
+                link(EntryType::Symlink, "e", "l/Cargo.toml"),
+            ],
+            LIMITS,
+            "goes through a symlink",
+        );
+    }
+
+    #[test]
+    fn rejects_entries_beneath_symlinks() {
+        assert_rejected(
+            &[link(EntryType::Symlink, "l", "."), file("l/escape", b"x")],
+            LIMITS,
+            "",
+        );
+    }
+
+    #[test]
+    fn rejects_hard_links_leaving_the_root() {
+        assert_rejected(
+            &[link(EntryType::Link, "h", "../../etc/passwd")],
+            LIMITS,
+            "",
+        );
+        assert_rejected(&[link(EntryType::Link, "h", "/etc/passwd")], LIMITS, "");
+        assert_rejected(
+            &[
+                link(EntryType::Symlink, "l", "."),
+                link(EntryType::Link, "h", "l/x"),
+            ],
+            LIMITS,
+            "",
+        );
+    }
+
+    #[test]
+    fn rejects_device_nodes_and_fifos() {
+        assert_rejected(
+            &[link(EntryType::Char, "null", "")],
+            LIMITS,
+            "device nodes are not allowed",
+        );
+        assert_rejected(
+            &[link(EntryType::Block, "sda", "")],
+            LIMITS,
+            "device nodes are not allowed",
+        );
+        assert_rejected(
+            &[link(EntryType::Fifo, "pipe", "")],
+            LIMITS,
+            "FIFOs are not allowed",
+        );
+    }
+
+    #[test]
+    fn rejects_setuid_and_setgid_bits() {
+        for mode in [0o4755, 0o2755] {
+            assert_rejected(
+                &[Entry {
+                    mode,
+                    ..file("run", b"x")
+                }],
+                LIMITS,
+                "",
+            );
+        }
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let entries = ExtractLimits {
+            max_entries: 2,
+            ..LIMITS
+        };
+        assert_rejected(
+            &[file("a", b""), file("b", b""), file("c", b"")],
+            entries,
+            "",
+        );
+
+        let file_size = ExtractLimits {
+            max_file_bytes: 4,
+            ..LIMITS
+        };
+        assert_rejected(&[file("a", b"12345")], file_size, "");
+
+        let total = ExtractLimits {
+            max_total_bytes: 8,
+            ..LIMITS
+        };
+        assert_rejected(&[file("a", b"12345"), file("b", b"12345")], total, "");
+    }
+
+    fn headers(pairs: &[(header::HeaderName, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.clone(), header::HeaderValue::from_static(value)))
+            .collect()
+    }
+
+    #[test]
+    fn detects_encoding_from_headers() {
+        use header::{CONTENT_ENCODING, CONTENT_TYPE};
+        let gzip_body = [0x1f, 0x8b, 0x08];
+        let cases: &[(&[(header::HeaderName, &str)], UploadEncoding)] = &[
+            (&[(CONTENT_ENCODING, "zstd")], UploadEncoding::Zstd),
+            (&[(CONTENT_ENCODING, " X-XZ ")], UploadEncoding::Xz),
+            // An explicit encoding wins over the type and the body.
+            (
+                &[
+                    (CONTENT_ENCODING, "identity"),
+                    (CONTENT_TYPE, "application/gzip"),
+                ],
+                UploadEncoding::Identity,
+            ),
+            (
+                &[(CONTENT_ENCODING, "xz"), (CONTENT_TYPE, "application/zstd")],
+                UploadEncoding::Xz,
+            ),
+            // The type wins over the body.
+            (&[(CONTENT_TYPE, "application/zstd")], UploadEncoding::Zstd),
+            (
+                &[(CONTENT_TYPE, "application/x-xz; charset=binary")],
+                UploadEncoding::Xz,
+            ),
+            // Types that say nothing about compression leave it to the body.
+            (&[(CONTENT_TYPE, "application/x-tar")], UploadEncoding::Gzip),
+            (&[], UploadEncoding::Gzip),
+        ];
+        for (pairs, expected) in cases {
+            assert_eq!(
+                UploadEncoding::detect(&headers(pairs), &gzip_body),
+                Ok(*expected),
+                "{pairs:?}"
+            );
+        }
+        assert_eq!(
+            UploadEncoding::detect(&headers(&[(CONTENT_ENCODING, "br")]), &gzip_body),
+            Err("Unsupported Content-Encoding: br".to_string())
+        );
+    }
+
+    #[test]
+    fn detects_encoding_from_magic_bytes() {
+        use std::io::Write;
+        let tar = archive(&[file("Cargo.toml", b"[package]")]);
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&tar).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let zstd = zstd::encode_all(tar.as_slice(), 0).unwrap();
+        let mut xz = xz2::write::XzEncoder::new(Vec::new(), 6);
+        xz.write_all(&tar).unwrap();
+        let xz = xz.finish().unwrap();
+
+        for (body, expected) in [
+            (&gzip, UploadEncoding::Gzip),
+            (&zstd, UploadEncoding::Zstd),
+            (&xz, UploadEncoding::Xz),
+            (&tar, UploadEncoding::Identity),
+        ] {
+            assert_eq!(
+                UploadEncoding::detect(&HeaderMap::new(), body),
+                Ok(expected)
+            );
+        }
+    }
+}
@@ -0,0 +1,640 @@
+use axum::{
+    Json,
+    body::Body,
+    extract::{Path, Query, Request, State},
+    http::StatusCode,
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use futures_util::{StreamExt, stream};
+use serde::Deserialize;
+use std::{
+    convert::Infallible,
+    io::{Read, Seek},
+    sync::Arc,
+    time::Instant,
+};
+use tempfile::TempDir;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info, warn};
+
+use crate::AppState;
+use crate::build::{Artifact, ArtifactKind};
+use crate::capabilities::Capabilities;
+use crate::extract::{self, ExtractError, UploadEncoding};
+use crate::health;
+use crate::jobs::{Job, JobState};
+use crate::logs::LogLine;
+use crate::params::{CompileParams, Compression};
+use crate::queue::QueueFull;
+use crate::spool::{self, SpoolError};
+use crate::toolchain::Toolchain;
+use crate::uploads::{BlobError, MaterializeError, UploadManifest};
+
+/// Suggested back-off for clients turned away by a full build queue.
+const QUEUE_FULL_RETRY_AFTER_SECS: u32 = 10;
+
+pub async fn targets_handler(State(state): State<Arc<AppState>>) -> Json<Toolchain> {
+    Json(state.toolchain.read().unwrap().clone())
+}
+
+#[derive(Deserialize)]
+pub struct CapabilitiesParams {
+    /// Probe toolchains and hardware again instead of returning the last snapshot.
+    #[serde(default)]
+    refresh: bool,
+}
+
+pub async fn capabilities_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<CapabilitiesParams>,
+) -> Json<Capabilities> {
+    if params.refresh {
+        let features = state.capabilities.read().unwrap().features.clone();
+        let (toolchain, fresh) = tokio::join!(
+            Toolchain::detect(),
+            Capabilities::collect(state.config.work_dir.clone(), features)
+        );
+        state.jobs.set_toolchain(&toolchain);
+        *state.toolchain.write().unwrap() = toolchain;
+        *state.capabilities.write().unwrap() = fresh;
+    }
+    Json(state.capabilities.read().unwrap().clone())
+}
+
+pub async fn metrics_handler(State(state): State<Arc<AppState>>) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", prometheus::TEXT_FORMAT)
+        .body(Body::from(state.metrics.render(&state).await))
+        .unwrap()
+}
+
+/// Liveness: answers as long as the process serves requests.
+pub async fn healthz_handler() -> &'static str {
+    "ok"
+}
+
+/// Readiness: whether builds sent here now are expected to succeed.
+pub async fn readyz_handler(State(state): State<Arc<AppState>>) -> Response<Body> {
+    let readiness = health::readiness(&state).await;
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    json_response(status, &readiness)
+}
+
+/// Synchronous build: submits a job and holds the connection until it finishes.
+pub async fn compile_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<CompileParams>,
+    req: Request<Body>,
+) -> Response<Body> {
+    info!(crate_name = %params.crate_name, profile = %params.profile, "Received build");
+
+    let job = match submit(&state, params, req).await {
+        Ok(job) => job,
+        Err(response) => return response,
+    };
+
+    // Dropped along with this future if the client disconnects before the build ends.
+    let _cancel_on_disconnect = CancelOnDrop(Arc::clone(&job));
+
+    job.wait().await;
+    job_result_response(&job)
+}
+
+/// Cancels a job that is still queued or running when dropped.
+struct CancelOnDrop(Arc<Job>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if self.0.cancel() {
+            info!(job_id = %self.0.id, "Client disconnected, cancelling job");
+        }
+    }
+}
+
+pub async fn submit_job_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<CompileParams>,
+    req: Request<Body>,
+) -> Response<Body> {
+    info!(crate_name = %params.crate_name, profile = %params.profile, "Received build");
+
+    let job = match submit(&state, params, req).await {
+        Ok(job) => job,
+        Err(response) => return response,
+    };
+
+    let mut response = json_response(StatusCode::ACCEPTED, &job.view());
+    response
+        .headers_mut()
+        .insert("Location", format!("/jobs/{}", job.id).parse().unwrap());
+    response
+}
+
+pub async fn job_status_handler(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Response<Body> {
+    match state.jobs.get(&id) {
+        Some(job) => json_response(StatusCode::OK, &job.view()),
+        None => job_not_found(&id),
+    }
+}
+
+pub async fn job_artifact_handler(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Response<Body> {
+    let Some(job) = state.jobs.get(&id) else {
+        return job_not_found(&id);
+    };
+
+    match job.state() {
+        JobState::Queued | JobState::Running => {
+            error_response(StatusCode::CONFLICT, "Job has not finished yet")
+        }
+        _ => job_result_response(&job),
+    }
+}
+
+/// Streams a job's cargo output as Server-Sent Events.
+///
+/// Output produced before the client connected is replayed first. Each line is an
+/// `stdout` or `stderr` event, and a final `end` event carries the job's state.
+pub async fn job_logs_handler(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Response<Body> {
+    let Some(job) = state.jobs.get(&id) else {
+        return job_not_found(&id);
+    };
+
+    let (replay, live) = job.log.subscribe();
+
+    let replayed = stream::iter(replay.into_iter().map(log_event));
+    let live = stream::unfold(live, |rx| async move {
+        let mut rx = rx?;
+        match rx.recv().await {
+            Ok(line) => Some((log_event(line), Some(rx))),
+            Err(RecvError::Lagged(skipped)) => Some((
+                Event::default().comment(format!("skipped {} lines", skipped)),
+                Some(rx),
+            )),
+            Err(RecvError::Closed) => None,
+        }
+    });
+    let end =
+        stream::once(async move { Event::default().event("end").json_data(job.view()).unwrap() });
+
+    Sse::new(replayed.chain(live).chain(end).map(Ok::<_, Infallible>))
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+fn log_event(line: LogLine) -> Event {
+    Event::default()
+        .event(line.stream.as_str())
+        .json_data(&line)
+        .unwrap()
+}
+
+pub async fn cancel_job_handler(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Response<Body> {
+    let Some(job) = state.jobs.get(&id) else {
+        return job_not_found(&id);
+    };
+
+    if !job.cancel() {
+        return error_response(StatusCode::CONFLICT, "Job has already finished");
+    }
+
+    info!(job_id = %job.id, "Cancellation requested");
+    json_response(StatusCode::ACCEPTED, &job.view())
+}
+
+/// Source of a build's workspace other than a tarball in the request body.
+#[derive(Deserialize)]
+struct SourceParams {
+    /// A delta upload registered through `POST /uploads`.
+    upload_id: Option<String>,
+}
+
+/// Registers a workspace manifest and replies with the blobs the client still has to upload.
+pub async fn upload_manifest_handler(
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+) -> Response<Body> {
+    if state.uploads.is_none() {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "Delta uploads are disabled on this worker",
+        );
+    }
+
+    let max_bytes = state.config.max_manifest_bytes;
+    if let Some(response) = reject_oversized(&req, max_bytes) {
+        return response;
+    }
+    let bytes = match spool::collect_limited(req.into_body(), max_bytes).await {
+        Ok(bytes) => bytes,
+        Err(e) => return spool_error_response(e),
+    };
+    let manifest: UploadManifest = match serde_json::from_slice(&bytes) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            return error_response(StatusCode::BAD_REQUEST, &format!("Invalid manifest: {}", e));
+        }
+    };
+    let registered = {
+        let state = Arc::clone(&state);
+        blocking(move || {
+            let uploads = state.uploads.as_ref().expect("checked above");
+            uploads.validate(&manifest)?;
+            Ok::<_, String>(uploads.register(manifest))
+        })
+        .await
+    };
+    let session = match registered {
+        Ok(Ok(session)) => session,
+        Ok(Err(e)) => return error_response(StatusCode::BAD_REQUEST, &e),
+        Err(response) => return response,
+    };
+    info!(
+        upload_id = %session.upload_id,
+        missing_blobs = session.missing.len(),
+        "Upload registered"
+    );
+    json_response(StatusCode::OK, &session)
+}
+
+pub async fn put_blob_handler(
+    State(state): State<Arc<AppState>>,
+    Path(hash): Path<String>,
+    req: Request<Body>,
+) -> Response<Body> {
+    let Some(uploads) = &state.uploads else {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "Delta uploads are disabled on this worker",
+        );
+    };
+
+    if let Some(response) = reject_oversized(&req, state.config.max_body_bytes) {
+        return response;
+    }
+    let spooled = match spool::spool(
+        req.into_body(),
+        uploads.staging_dir(),
+        state.config.max_body_bytes,
+    )
+    .await
+    {
+        Ok(spooled) => spooled,
+        Err(e) => return spool_error_response(e),
+    };
+
+    let stored = {
+        let state = Arc::clone(&state);
+        let hash = hash.clone();
+        blocking(move || {
+            let uploads = state.uploads.as_ref().expect("checked above");
+            uploads.put_blob(&hash, spooled)
+        })
+        .await
+    };
+    let stored = match stored {
+        Ok(stored) => stored,
+        Err(response) => return response,
+    };
+
+    match stored {
+        Ok(()) => Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap(),
+        Err(BlobError::InvalidHash) => error_response(
+            StatusCode::BAD_REQUEST,
+            "Blob name must be a lowercase hex SHA-256",
+        ),
+        Err(BlobError::HashMismatch(actual)) => error_response(
+            StatusCode::BAD_REQUEST,
+            &format!("Content hashes to {}, not {}", actual, hash),
+        ),
+        Err(BlobError::Io(e)) => {
+            error!(%hash, error = ?e, "Failed to store blob");
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to store blob")
+        }
+    }
+}
+
+/// Validates the request, prepares the workspace and queues a build for it.
+///
+/// The workspace is either the tarball in the body or, with `upload_id`, a tree
+/// materialized from the blob store.
+async fn submit(
+    state: &Arc<AppState>,
+    params: CompileParams,
+    req: Request<Body>,
+) -> Result<Arc<Job>, Response<Body>> {
+    if let Err(e) = params.validate() {
+        return Err(error_response(StatusCode::BAD_REQUEST, &e));
+    }
+
+    if let Some(target) = &params.target {
+        let toolchain = state.toolchain.read().unwrap();
+        if !toolchain.supports_target(target) {
+            return Err(error_response(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                &format!(
+                    "Target {} is not installed on this worker (available: {})",
+                    target,
+                    toolchain.targets.join(", ")
+                ),
+            ));
+        }
+    }
+
+    if params.workspace_key.is_some() && !state.jobs.persistent_workspaces_enabled() {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            "Persistent workspaces are disabled on this worker",
+        ));
+    }
+
+    if state.health.is_draining() {
+        return Err(error_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Worker is shutting down",
+        ));
+    }
+
+    // Turn builds away before reading a possibly large body they would be refused for.
+    if state.jobs.queue_full() {
+        return Err(queue_full_response());
+    }
+
+    let source = Query::<SourceParams>::try_from_uri(req.uri())
+        .map(|Query(source)| source)
+        .unwrap_or(SourceParams { upload_id: None });
+
+    let workspace = match source.upload_id {
+        Some(upload_id) => materialize_workspace(state, &upload_id).await?,
+        None => unpack_workspace(state, req).await?,
+    };
+    let timeout = state.config.build_timeout(params.timeout_secs);
+    state
+        .jobs
+        .submit(params, workspace, timeout)
+        .await
+        .map_err(|QueueFull| queue_full_response())
+}
+
+async fn materialize_workspace(
+    state: &Arc<AppState>,
+    upload_id: &str,
+) -> Result<TempDir, Response<Body>> {
+    if state.uploads.is_none() {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            "Delta uploads are disabled on this worker",
+        ));
+    };
+
+    let temp_dir = match tempfile::Builder::new().tempdir_in(state.config.jobs_dir()) {
+        Ok(dir) => dir,
+        Err(_) => {
+            return Err(error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Temp dir error",
+            ));
+        }
+    };
+
+    let materialized = {
+        let state = Arc::clone(state);
+        let upload_id = upload_id.to_string();
+        let dest = temp_dir.path().to_path_buf();
+        blocking(move || {
+            let uploads = state.uploads.as_ref().expect("checked above");
+            uploads.materialize(&upload_id, &dest)
+        })
+        .await?
+    };
+
+    match materialized {
+        Ok(()) => Ok(temp_dir),
+        Err(MaterializeError::UnknownUpload) => Err(error_response(
+            StatusCode::NOT_FOUND,
+            &format!("No such upload: {}", upload_id),
+        )),
+        Err(MaterializeError::Rejected(reason)) => {
+            Err(error_response(StatusCode::BAD_REQUEST, &reason))
+        }
+        Err(MaterializeError::MissingBlobs(missing)) => Err(json_response(
+            StatusCode::CONFLICT,
+            &serde_json::json!({ "missing": missing }),
+        )),
+        Err(MaterializeError::InvalidEntry(entry, reason)) => Err(error_response(
+            StatusCode::BAD_REQUEST,
+            &format!("Rejected manifest entry {}: {}", entry, reason),
+        )),
+        Err(MaterializeError::Io(e)) => {
+            error!(%upload_id, error = ?e, "Failed to materialize upload");
+            Err(error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Materialize failed",
+            ))
+        }
+    }
+}
+
+async fn unpack_workspace(state: &AppState, req: Request<Body>) -> Result<TempDir, Response<Body>> {
+    let config = &state.config;
+    if let Some(response) = reject_oversized(&req, config.max_body_bytes) {
+        return Err(response);
+    }
+
+    let (parts, body) = req.into_parts();
+    let spooled = match spool::spool(body, &config.jobs_dir(), config.max_body_bytes).await {
+        Ok(spooled) => spooled,
+        Err(e) => return Err(spool_error_response(e)),
+    };
+    let mut archive = match spooled.reopen() {
+        Ok(file) => file,
+        Err(e) => {
+            error!(error = ?e, "Failed to reopen spooled upload");
+            return Err(error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to read upload",
+            ));
+        }
+    };
+
+    let mut magic = [0u8; 8];
+    let magic_len = archive.read(&mut magic).unwrap_or(0);
+    if archive.rewind().is_err() {
+        return Err(error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to read upload",
+        ));
+    }
+
+    let encoding = match UploadEncoding::detect(&parts.headers, &magic[..magic_len]) {
+        Ok(encoding) => encoding,
+        Err(e) => return Err(error_response(StatusCode::UNSUPPORTED_MEDIA_TYPE, &e)),
+    };
+
+    // Create temp directory
+    let temp_dir = match tempfile::Builder::new().tempdir_in(config.jobs_dir()) {
+        Ok(dir) => dir,
+        Err(_) => {
+            return Err(error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Temp dir error",
+            ));
+        }
+    };
+
+    // Unpack tarball
+    let dest = temp_dir.path().to_path_buf();
+    let limits = config.extract_limits;
+    let started = Instant::now();
+    match blocking(move || extract::unpack(archive, encoding, &dest, limits)).await? {
+        Ok(()) => {
+            let size = spooled.as_file().metadata().map_or(0, |m| m.len());
+            let elapsed = started.elapsed();
+            info!(
+                upload_bytes = size,
+                unpack_ms = elapsed.as_millis() as u64,
+                "Unpacked workspace"
+            );
+            state.metrics.observe_upload(size, elapsed);
+        }
+        Err(ExtractError::Rejected { entry, reason }) => {
+            warn!(%entry, %reason, "Rejected archive entry");
+            return Err(error_response(
+                StatusCode::BAD_REQUEST,
+                &format!("Rejected archive entry {}: {}", entry, reason),
+            ));
+        }
+        Err(ExtractError::Io(e)) => {
+            warn!(?encoding, error = ?e, "Failed to unpack archive");
+            return Err(error_response(StatusCode::BAD_REQUEST, "Unpack failed"));
+        }
+    }
+
+    Ok(temp_dir)
+}
+
+fn job_result_response(job: &Job) -> Response<Body> {
+    match job.take_result() {
+        Some(Ok(artifact)) => {
+            let mut response = artifact_response(&artifact);
+            let cache_status = if job.cached { "hit" } else { "miss" };
+            response
+                .headers_mut()
+                .insert("X-Cache", cache_status.parse().unwrap());
+            response
+        }
+        Some(Err(failure)) => match (&failure.diagnostics, failure.limit) {
+            (Some(report), _) => json_response(failure.status, &**report),
+            (None, Some(_)) => json_response(failure.status, &job.view()),
+            (None, None) => error_response(failure.status, &failure.message),
+        },
+        None => error_response(StatusCode::CONFLICT, "Job has not finished yet"),
+    }
+}
+
+fn artifact_response(artifact: &Artifact) -> Response<Body> {
+    let (header, content_type) = match artifact.kind {
+        ArtifactKind::Rlib => ("X-Rlib-File", "application/octet-stream"),
+        ArtifactKind::Library => ("X-Library-File", "application/octet-stream"),
+        ArtifactKind::Binary => ("X-Binary-File", "application/octet-stream"),
+        ArtifactKind::Archive(Compression::None) => ("X-Archive-File", "application/x-tar"),
+        ArtifactKind::Archive(Compression::Gzip) => ("X-Archive-File", "application/gzip"),
+    };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", content_type)
+        .header(header, &artifact.filename)
+        .body(Body::from(artifact.data.clone()))
+        .unwrap()
+}
+
+/// Runs file-heavy work on the blocking pool so it cannot stall the listener.
+async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> T + Send + 'static,
+) -> Result<T, Response<Body>> {
+    tokio::task::spawn_blocking(work).await.map_err(|e| {
+        error!(error = ?e, "Blocking task failed");
+        error_response(StatusCode::INTERNAL_SERVER_ERROR, "Internal error")
+    })
+}
+
+/// Answers `413` up front when the declared `Content-Length` is already too large.
+fn reject_oversized(req: &Request<Body>, max_bytes: u64) -> Option<Response<Body>> {
+    let length = req
+        .headers()
+        .get("Content-Length")?
+        .to_str()
+        .ok()?
+        .parse::<u64>()
+        .ok()?;
+    (length > max_bytes).then(|| spool_error_response(SpoolError::TooLarge(max_bytes)))
+}
+
+fn spool_error_response(e: SpoolError) -> Response<Body> {
+    match e {
+        SpoolError::TooLarge(max) => error_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            &format!("Request body exceeds {} bytes", max),
+        ),
+        SpoolError::Read(e) => {
+            warn!(error = %e, "Failed to read request body");
+            error_response(StatusCode::BAD_REQUEST, "Failed to collect body")
+        }
+        SpoolError::Io(e) => {
+            error!(error = ?e, "Failed to spool request body");
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to store body")
+        }
+    }
+}
+
+fn queue_full_response() -> Response<Body> {
+    let mut response = error_response(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Build queue is full, retry later",
+    );
+    response
+        .headers_mut()
+        .insert("Retry-After", QUEUE_FULL_RETRY_AFTER_SECS.into());
+    response
+}
+
+fn job_not_found(id: &str) -> Response<Body> {
+    error_response(StatusCode::NOT_FOUND, &format!("No such job: {}", id))
+}
+
+fn json_response<T: serde::Serialize>(status: StatusCode, value: &T) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_vec(value).unwrap()))
+        .unwrap()
+}
+
+pub fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message.to_string()))
+        .unwrap()
+}
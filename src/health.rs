@@ -0,0 +1,192 @@
+//! Liveness and readiness: the startup self-test, draining on shutdown, and the
+//! checks behind `/readyz`.
+
+use axum::{
+    body::{Body, to_bytes},
+    extract::{Query, State},
+    http::{Request, StatusCode},
+};
+use serde::Serialize;
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+use tracing::{Instrument, error, info, info_span, warn};
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::capabilities::disk_space;
+use crate::handlers;
+
+/// Crate name of the embedded self-test package.
+const SELF_TEST_CRATE: &str = "distbuild_self_test";
+
+const SELF_TEST_MANIFEST: &str = r#"[package]
+name = "distbuild_self_test"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+path = "src/lib.rs"
+"#;
+
+#[derive(Clone, Serialize)]
+#[serde(tag = "state", content = "error", rename_all = "snake_case")]
+pub enum SelfTest {
+    Pending,
+    Passed,
+    Failed(String),
+}
+
+pub struct Health {
+    self_test: Mutex<SelfTest>,
+    draining: AtomicBool,
+}
+
+#[derive(Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub self_test: SelfTest,
+    /// Why the worker is not ready; empty when it is.
+    pub reasons: Vec<String>,
+}
+
+impl Health {
+    pub fn new() -> Health {
+        Health {
+            self_test: Mutex::new(SelfTest::Pending),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Stops accepting builds; readiness fails from now on.
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Evaluates every readiness condition, so the response lists all that fail.
+pub async fn readiness(state: &AppState) -> Readiness {
+    let self_test = state.health.self_test.lock().unwrap().clone();
+    let mut reasons = Vec::new();
+
+    match &self_test {
+        SelfTest::Pending => reasons.push("Self-test has not finished".to_string()),
+        SelfTest::Failed(_) => reasons.push("Self-test failed".to_string()),
+        SelfTest::Passed => {}
+    }
+    if state.health.is_draining() {
+        reasons.push("Worker is draining".to_string());
+    }
+    if state.jobs.queue_full() {
+        reasons.push("Build queue is full".to_string());
+    }
+
+    let work_dir = state.config.work_dir.clone();
+    let min_free = state.config.min_free_disk_bytes;
+    match tokio::task::spawn_blocking(move || disk_space(&work_dir)).await {
+        Ok(Ok((_, free))) if free < min_free => reasons.push(format!(
+            "Only {} bytes free in the work directory, below {}",
+            free, min_free
+        )),
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => reasons.push(format!("Cannot stat the work directory: {}", e)),
+        Err(e) => reasons.push(format!("Disk check panicked: {}", e)),
+    }
+
+    Readiness {
+        ready: reasons.is_empty(),
+        self_test,
+        reasons,
+    }
+}
+
+/// Builds a tiny embedded crate through the same path as `POST /compile`, so the
+/// worker only becomes ready once unpacking, the sandbox, cargo and the toolchain
+/// all work.
+pub async fn run_self_test(state: Arc<AppState>) {
+    let span = info_span!("self_test");
+    span.in_scope(|| info!("Running startup self-test"));
+    let result = self_test(&state).instrument(span.clone()).await;
+    span.in_scope(|| match &result {
+        Ok(()) => info!("Self-test passed, worker is ready"),
+        Err(e) => error!(error = %e, "Self-test failed, worker stays unready"),
+    });
+    *state.health.self_test.lock().unwrap() = match result {
+        Ok(()) => SelfTest::Passed,
+        Err(e) => SelfTest::Failed(e),
+    };
+}
+
+async fn self_test(state: &Arc<AppState>) -> Result<(), String> {
+    let tarball = self_test_tarball().map_err(|e| format!("Cannot pack self-test crate: {}", e))?;
+    let request = Request::builder()
+        .method("POST")
+        .uri(format!("/compile?crate_name={}", SELF_TEST_CRATE))
+        .header("Content-Type", "application/x-tar")
+        .body(Body::from(tarball))
+        .unwrap();
+    let Ok(params) = Query::try_from_uri(request.uri()) else {
+        return Err("Invalid self-test parameters".to_string());
+    };
+
+    let response = handlers::compile_handler(State(Arc::clone(state)), params, request).await;
+    if response.status() == StatusCode::OK {
+        return Ok(());
+    }
+    let status = response.status();
+    let body = to_bytes(response.into_body(), 64 * 1024)
+        .await
+        .unwrap_or_default();
+    Err(format!(
+        "{}: {}",
+        status,
+        String::from_utf8_lossy(&body).trim()
+    ))
+}
+
+/// The self-test crate. A fresh constant in its source makes every run miss the
+/// build cache, so cargo really compiles it.
+fn self_test_tarball() -> std::io::Result<Vec<u8>> {
+    let lib = format!(
+        "pub const RUN: &str = \"{}\";\n\npub fn add(a: u64, b: u64) -> u64 {{\n    a + b\n}}\n",
+        Uuid::new_v4()
+    );
+    let mut builder = tar::Builder::new(Vec::new());
+    for (path, contents) in [("Cargo.toml", SELF_TEST_MANIFEST), ("src/lib.rs", &lib)] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, path, contents.as_bytes())?;
+    }
+    builder.into_inner()
+}
+
+/// Waits for queued and running builds to finish, for at most `timeout`.
+pub async fn drain(state: &AppState, timeout: Duration) {
+    let finished = async {
+        loop {
+            let load = state.jobs.load();
+            if load.running == 0 && load.queued == 0 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        }
+    };
+    if tokio::time::timeout(timeout, finished).await.is_err() {
+        let load = state.jobs.load();
+        warn!(
+            running = load.running,
+            queued = load.queued,
+            "Drain timed out with builds still in flight"
+        );
+    }
+}
@@ -0,0 +1,489 @@
+use axum::http::StatusCode;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tempfile::TempDir;
+use tokio::sync::watch;
+use tracing::{Instrument, error, info, info_span, warn};
+use uuid::Uuid;
+
+use crate::build::{self, Artifact, BuildContext, BuildError};
+use crate::cache::BuildCache;
+use crate::limits::{Limit, Limiter};
+use crate::logs::JobLog;
+use crate::messages::DiagnosticReport;
+use crate::metrics::Metrics;
+use crate::params::CompileParams;
+use crate::queue::{ExecutorQueue, QueueFull, QueueLoad, Ticket};
+use crate::sandbox::Sandbox;
+use crate::toolchain::Toolchain;
+use crate::workspaces::Workspaces;
+
+/// How long finished jobs stay queryable before they are dropped.
+const JOB_RETENTION: Duration = Duration::from_secs(600);
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+    TimedOut,
+    ResourceExhausted,
+}
+
+impl JobState {
+    pub fn name(self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Succeeded => "succeeded",
+            JobState::Failed => "failed",
+            JobState::Cancelled => "cancelled",
+            JobState::TimedOut => "timed_out",
+            JobState::ResourceExhausted => "resource_exhausted",
+        }
+    }
+
+    pub fn is_finished(self) -> bool {
+        matches!(
+            self,
+            JobState::Succeeded
+                | JobState::Failed
+                | JobState::Cancelled
+                | JobState::TimedOut
+                | JobState::ResourceExhausted
+        )
+    }
+}
+
+#[derive(Clone)]
+pub struct JobFailure {
+    pub status: StatusCode,
+    pub message: String,
+    /// Compiler diagnostics, when cargo itself ran and reported the failure.
+    pub diagnostics: Option<Arc<DiagnosticReport>>,
+    /// The resource limit the build ran into.
+    pub limit: Option<Limit>,
+}
+
+pub type JobResult = Result<Arc<Artifact>, JobFailure>;
+
+pub struct Job {
+    pub id: String,
+    pub params: CompileParams,
+    state: watch::Sender<JobState>,
+    cancel: watch::Sender<bool>,
+    /// `Ok(None)` once the artifact has been handed out by [`Job::take_result`].
+    result: Mutex<Option<Result<Option<Arc<Artifact>>, JobFailure>>>,
+    pub log: Arc<JobLog>,
+    /// Whether the result was served from the build cache.
+    pub cached: bool,
+    /// How long cargo may run once the job has an executor slot.
+    pub timeout: Duration,
+    queue: Arc<ExecutorQueue>,
+}
+
+/// Snapshot of a job as reported by `GET /jobs/{id}`.
+#[derive(Serialize)]
+pub struct JobView {
+    pub id: String,
+    pub crate_name: String,
+    pub state: JobState,
+    pub cached: bool,
+    /// 1-based place in the queue while the job waits for an executor slot.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_position: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The resource limit a `resource_exhausted` job ran into.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<Limit>,
+}
+
+impl Job {
+    pub fn state(&self) -> JobState {
+        *self.state.borrow()
+    }
+
+    pub fn view(&self) -> JobView {
+        let (error, limit) = match &*self.result.lock().unwrap() {
+            Some(Err(failure)) => (Some(failure.message.clone()), failure.limit),
+            _ => (None, None),
+        };
+        JobView {
+            id: self.id.clone(),
+            crate_name: self.params.crate_name.clone(),
+            state: self.state(),
+            cached: self.cached,
+            queue_position: self.queue.position(&self.id),
+            error,
+            limit,
+        }
+    }
+
+    /// The outcome of the build, once the job has finished.
+    ///
+    /// A successful build's artifact is handed out only once and then dropped, so
+    /// finished jobs do not hold build output in memory for [`JOB_RETENTION`]. Later
+    /// calls fail with `410 Gone`.
+    pub fn take_result(&self) -> Option<JobResult> {
+        match self.result.lock().unwrap().as_mut()? {
+            Ok(artifact) => Some(artifact.take().ok_or_else(|| JobFailure {
+                status: StatusCode::GONE,
+                message: "Artifact was already fetched".to_string(),
+                diagnostics: None,
+                limit: None,
+            })),
+            Err(failure) => Some(Err(failure.clone())),
+        }
+    }
+
+    /// Waits until the job reaches a final state.
+    pub async fn wait(&self) -> JobState {
+        let mut rx = self.state.subscribe();
+        rx.wait_for(|state| state.is_finished())
+            .await
+            .map(|state| *state)
+            .expect("job state sender outlives the job")
+    }
+
+    /// Requests cancellation. Returns `false` if the job had already finished.
+    pub fn cancel(&self) -> bool {
+        if self.state().is_finished() {
+            return false;
+        }
+        self.cancel.send_replace(true);
+        true
+    }
+
+    fn finish(&self, state: JobState, result: JobResult) {
+        *self.result.lock().unwrap() = Some(result.map(Some));
+        self.state.send_replace(state);
+        self.log.close();
+    }
+}
+
+pub struct JobStore {
+    jobs: Mutex<HashMap<String, Arc<Job>>>,
+    cache: Option<BuildCache>,
+    workspaces: Option<Arc<Workspaces>>,
+    queue: Arc<ExecutorQueue>,
+    sandbox: Option<Sandbox>,
+    limiter: Limiter,
+    metrics: Arc<Metrics>,
+}
+
+impl JobStore {
+    pub fn new(
+        cache: Option<BuildCache>,
+        workspaces: Option<Workspaces>,
+        queue: ExecutorQueue,
+        sandbox: Option<Sandbox>,
+        limiter: Limiter,
+        metrics: Arc<Metrics>,
+    ) -> JobStore {
+        JobStore {
+            jobs: Mutex::new(HashMap::new()),
+            cache,
+            workspaces: workspaces.map(Arc::new),
+            queue: Arc::new(queue),
+            sandbox,
+            limiter,
+            metrics,
+        }
+    }
+
+    pub fn load(&self) -> QueueLoad {
+        self.queue.load()
+    }
+
+    /// Whether a build submitted now would be rejected with [`QueueFull`].
+    pub fn queue_full(&self) -> bool {
+        self.queue.is_full()
+    }
+
+    pub fn persistent_workspaces_enabled(&self) -> bool {
+        self.workspaces.is_some()
+    }
+
+    /// Keys build cache lookups to a newly detected toolchain.
+    pub fn set_toolchain(&self, toolchain: &Toolchain) {
+        if let Some(cache) = &self.cache {
+            cache.set_rustc_version(&toolchain.rustc_version);
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<Job>> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+
+    /// Registers a job for an unpacked workspace and starts building it in the background.
+    ///
+    /// When the build cache already holds a result for the same inputs, the job is
+    /// finished immediately with that result and cargo is never run. Otherwise the job
+    /// waits for an executor slot, or is refused when the queue is full.
+    pub async fn submit(
+        self: &Arc<Self>,
+        params: CompileParams,
+        workspace: TempDir,
+        timeout: Duration,
+    ) -> Result<Arc<Job>, QueueFull> {
+        let (cache_key, cached) = self.lookup_cache(workspace.path(), &params).await;
+
+        let id = Uuid::new_v4().to_string();
+        // Nested under the submitting request's span, so the build's logs carry its ID.
+        let span = info_span!("job", job_id = %id, crate_name = %params.crate_name);
+        let ticket = match cached {
+            Some(_) => None,
+            None => Some(self.queue.enqueue(&id)?),
+        };
+
+        let job = Arc::new(Job {
+            id,
+            params,
+            state: watch::Sender::new(JobState::Queued),
+            cancel: watch::Sender::new(false),
+            result: Mutex::new(None),
+            log: Arc::new(JobLog::default()),
+            cached: cached.is_some(),
+            timeout,
+            queue: Arc::clone(&self.queue),
+        });
+
+        self.jobs
+            .lock()
+            .unwrap()
+            .insert(job.id.clone(), Arc::clone(&job));
+
+        match (cached, ticket) {
+            (Some(artifact), _) => {
+                span.in_scope(|| info!("Served from cache"));
+                job.finish(JobState::Succeeded, Ok(Arc::new(artifact)));
+                tokio::spawn(Arc::clone(self).expire(Arc::clone(&job)));
+            }
+            (None, ticket) => {
+                let ticket = ticket.expect("uncached jobs are enqueued");
+                if let Some(position) = job.queue.position(&job.id) {
+                    span.in_scope(|| info!(position, "Queued"));
+                }
+                let run = Arc::clone(self).run(Arc::clone(&job), workspace, ticket, cache_key);
+                tokio::spawn(run.instrument(span));
+            }
+        }
+        Ok(job)
+    }
+
+    async fn run(
+        self: Arc<Self>,
+        job: Arc<Job>,
+        workspace: TempDir,
+        ticket: Ticket,
+        cache_key: Option<String>,
+    ) {
+        let started = Instant::now();
+        match self.execute(&job, &workspace, ticket).await {
+            Ok(artifact) => {
+                let artifact = Arc::new(artifact);
+                job.finish(JobState::Succeeded, Ok(Arc::clone(&artifact)));
+                if let Some(key) = cache_key {
+                    let store = Arc::clone(&self);
+                    tokio::task::spawn_blocking(move || {
+                        if let Some(cache) = &store.cache {
+                            cache.put(&key, &artifact);
+                        }
+                    });
+                }
+            }
+            Err(BuildError::Cancelled) => {
+                job.finish(JobState::Cancelled, Err(cancelled()));
+            }
+            Err(BuildError::TimedOut(timeout)) => {
+                let failure = JobFailure {
+                    status: StatusCode::GATEWAY_TIMEOUT,
+                    message: format!("Build exceeded its {}s timeout", timeout.as_secs()),
+                    diagnostics: None,
+                    limit: None,
+                };
+                job.finish(JobState::TimedOut, Err(failure));
+            }
+            Err(BuildError::CompileFailed(report)) => {
+                let failure = JobFailure {
+                    status: StatusCode::INTERNAL_SERVER_ERROR,
+                    message: format!("Compilation failed with {} errors", report.summary.errors),
+                    diagnostics: Some(Arc::new(report)),
+                    limit: None,
+                };
+                job.finish(JobState::Failed, Err(failure));
+            }
+            Err(BuildError::Failed(status, message)) => {
+                let failure = JobFailure {
+                    status,
+                    message,
+                    diagnostics: None,
+                    limit: None,
+                };
+                job.finish(JobState::Failed, Err(failure));
+            }
+            Err(BuildError::ResourceExhausted(limit)) => {
+                let failure = JobFailure {
+                    status: StatusCode::INTERNAL_SERVER_ERROR,
+                    message: format!("Build exceeded its {}", limit),
+                    diagnostics: None,
+                    limit: Some(limit),
+                };
+                job.finish(JobState::ResourceExhausted, Err(failure));
+            }
+        }
+
+        info!(
+            state = %job.state().name(),
+            total_ms = started.elapsed().as_millis() as u64,
+            "Job finished"
+        );
+        self.metrics.count_build(job.state());
+        drop(workspace);
+        self.expire(job).await;
+    }
+
+    /// Builds the job, in its persistent workspace when it has a `workspace_key`.
+    ///
+    /// The executor slot is taken before the workspace lease, so a job waiting for
+    /// its workspace keeps its slot rather than losing its place. The wait for the
+    /// lease can be cancelled and counts against the job's timeout, so a slot is never
+    /// held for longer than a build could run.
+    async fn execute(
+        &self,
+        job: &Job,
+        workspace: &TempDir,
+        ticket: Ticket,
+    ) -> Result<Artifact, BuildError> {
+        let mut cancel = job.cancel.subscribe();
+        let submitted = Instant::now();
+
+        let slot = tokio::select! {
+            slot = ticket.acquire() => slot,
+            _ = build::cancelled(&mut cancel) => return Err(BuildError::Cancelled),
+        };
+
+        let leased = Instant::now();
+        let lease = match (&job.params.workspace_key, &self.workspaces) {
+            (Some(key), Some(workspaces)) => tokio::select! {
+                lease = tokio::time::timeout(job.timeout, workspaces.acquire(key)) => match lease {
+                    Ok(lease) => Some(lease.map_err(workspace_error)?),
+                    Err(_) => return Err(BuildError::TimedOut(job.timeout)),
+                },
+                _ = build::cancelled(&mut cancel) => return Err(BuildError::Cancelled),
+            },
+            _ => None,
+        };
+        let timeout = job.timeout.saturating_sub(leased.elapsed());
+
+        let cancelled_early = *cancel.borrow();
+        if cancelled_early {
+            return Err(BuildError::Cancelled);
+        }
+
+        let (src_dir, target_dir) = match &lease {
+            Some(lease) => {
+                lease
+                    .replace_sources(workspace.path())
+                    .await
+                    .map_err(workspace_error)?;
+                (lease.src_dir(), Some(lease.target_dir()))
+            }
+            None => (workspace.path().to_path_buf(), None),
+        };
+
+        job.state.send_replace(JobState::Running);
+        info!(
+            queue_ms = submitted.elapsed().as_millis() as u64,
+            "Building"
+        );
+
+        let ctx = BuildContext {
+            job_id: &job.id,
+            workspace: &src_dir,
+            target_dir: target_dir.as_deref(),
+            sandbox: self.sandbox.as_ref(),
+            limiter: &self.limiter,
+        };
+        let started = Instant::now();
+        let result =
+            match build::run_build(ctx, &job.params, job.log.clone(), cancel, timeout).await {
+                Err(BuildError::TimedOut(_)) => Err(BuildError::TimedOut(job.timeout)),
+                result => result,
+            };
+        self.metrics.observe_build(started.elapsed());
+        drop(slot);
+
+        if let Some(lease) = lease {
+            drop(lease);
+            let workspaces = Arc::clone(self.workspaces.as_ref().unwrap());
+            tokio::task::spawn_blocking(move || workspaces.evict());
+        }
+
+        result
+    }
+
+    /// Hashes the workspace and looks the result up in the build cache.
+    ///
+    /// Hashing reads every source file, so it runs on the blocking pool.
+    async fn lookup_cache(
+        self: &Arc<Self>,
+        workspace: &Path,
+        params: &CompileParams,
+    ) -> (Option<String>, Option<Artifact>) {
+        if self.cache.is_none() {
+            return (None, None);
+        }
+
+        let store = Arc::clone(self);
+        let workspace = workspace.to_path_buf();
+        let params = params.clone();
+        let lookup = tokio::task::spawn_blocking(move || {
+            let cache = store.cache.as_ref().expect("checked above");
+            let key = cache
+                .key(&workspace, &params)
+                .inspect_err(|e| warn!(error = ?e, "Failed to compute cache key"))
+                .ok();
+            let cached = key.as_deref().and_then(|key| cache.get(key));
+            (key, cached)
+        });
+        let (key, cached) = lookup.await.unwrap_or_else(|e| {
+            warn!(error = ?e, "Cache lookup panicked");
+            (None, None)
+        });
+        self.metrics.observe_cache_lookup(cached.is_some());
+        (key, cached)
+    }
+
+    /// Keeps a finished job queryable for [`JOB_RETENTION`], then forgets it.
+    async fn expire(self: Arc<Self>, job: Arc<Job>) {
+        tokio::time::sleep(JOB_RETENTION).await;
+        self.jobs.lock().unwrap().remove(&job.id);
+    }
+}
+
+fn workspace_error(e: std::io::Error) -> BuildError {
+    error!(error = ?e, "Failed to prepare persistent workspace");
+    BuildError::Failed(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to prepare persistent workspace".to_string(),
+    )
+}
+
+fn cancelled() -> JobFailure {
+    JobFailure {
+        status: StatusCode::CONFLICT,
+        message: "Job was cancelled".to_string(),
+        diagnostics: None,
+        limit: None,
+    }
+}
@@ -0,0 +1,354 @@
+//! Per-job CPU, memory, process, file size and disk limits.
+//!
+//! Each build gets its own cgroup v2 when the worker runs in a cgroup it may manage
+//! (e.g. a systemd unit with `Delegate=yes`). Otherwise the memory limit falls back to
+//! `RLIMIT_DATA`, which applies per process rather than per build, and the process
+//! limit to `RLIMIT_NPROC`, which counts every process of the worker's user. That
+//! would let one build push another over the limit, so it is only used with a single
+//! executor slot. The CPU limit is not enforced. The file size limit is always a `setrlimit`. The disk
+//! limit is a quota on how much the build's workspace and target directory grow,
+//! checked while the build runs; a reused target directory only counts what the
+//! build adds to it.
+
+use serde::Serialize;
+use std::{
+    ffi::CString,
+    fmt, fs, io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::process::Command;
+use tracing::{info, warn};
+
+use crate::util::dir_size;
+
+/// How often a running build's disk usage is measured.
+const DISK_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[cfg(target_env = "gnu")]
+type Resource = libc::__rlimit_resource_t;
+#[cfg(not(target_env = "gnu"))]
+type Resource = libc::c_int;
+
+/// Limits for one build; `0` leaves a resource unlimited.
+#[derive(Clone, Copy)]
+pub struct ResourceLimits {
+    /// CPU time per wall-clock second, in cores.
+    pub cpus: f64,
+    pub memory_bytes: u64,
+    pub pids: u64,
+    /// Largest file a build process may write.
+    pub file_bytes: u64,
+    /// How much the build's workspace and target directory may grow while it runs.
+    pub disk_bytes: u64,
+}
+
+impl ResourceLimits {
+    fn any(&self) -> bool {
+        self.cpus > 0.0 || self.memory_bytes > 0 || self.pids > 0 || self.file_bytes > 0
+    }
+}
+
+/// The limit a failed build ran into.
+#[derive(Clone, Copy, Serialize)]
+#[serde(tag = "resource", content = "value", rename_all = "snake_case")]
+pub enum Limit {
+    Memory(u64),
+    Pids(u64),
+    FileSize(u64),
+    Disk(u64),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Limit::Memory(bytes) => write!(f, "memory limit of {} bytes", bytes),
+            Limit::Pids(pids) => write!(f, "limit of {} processes", pids),
+            Limit::FileSize(bytes) => write!(f, "file size limit of {} bytes", bytes),
+            Limit::Disk(bytes) => write!(f, "disk limit of {} bytes", bytes),
+        }
+    }
+}
+
+pub struct Limiter {
+    limits: ResourceLimits,
+    /// Parent of the per-job cgroups, when cgroup v2 is usable.
+    cgroups: Option<PathBuf>,
+}
+
+impl Limiter {
+    /// Sets up cgroup v2 delegation when any limit is configured and the host allows it.
+    ///
+    /// `executor_slots` is how many builds may run at once.
+    pub fn new(mut limits: ResourceLimits, executor_slots: usize) -> Limiter {
+        let cgroups = if limits.any() {
+            delegate_cgroup()
+                .inspect_err(
+                    |e| warn!(error = %e, "cgroup v2 unavailable, limiting builds with setrlimit"),
+                )
+                .ok()
+        } else {
+            None
+        };
+        if limits.cpus > 0.0 && cgroups.is_none() {
+            warn!("WORKER_JOB_CPUS is only enforced with cgroup v2");
+        }
+        if limits.pids > 0 && cgroups.is_none() && executor_slots > 1 {
+            warn!("WORKER_JOB_PIDS is only enforced with cgroup v2 or a single executor slot");
+            limits.pids = 0;
+        }
+        if let Some(dir) = &cgroups {
+            info!(dir = %dir.display(), "Build limits enforced with cgroups");
+        }
+        Limiter { limits, cgroups }
+    }
+
+    /// Makes `command` run under the configured limits. The returned guard reports
+    /// which limit a failed build hit, and removes the job's cgroup when dropped.
+    ///
+    /// Must be called before the sandbox is applied, so the child joins its cgroup
+    /// while still in the worker's namespaces.
+    pub fn apply(&self, command: &mut Command, job_id: &str) -> io::Result<JobLimits> {
+        let limits = self.limits;
+        let cgroup = match &self.cgroups {
+            Some(parent) if limits.memory_bytes > 0 || limits.pids > 0 || limits.cpus > 0.0 => {
+                Some(create_job_cgroup(parent, job_id, &limits)?)
+            }
+            _ => None,
+        };
+
+        let procs = match &cgroup {
+            Some(dir) => Some(CString::new(
+                dir.join("cgroup.procs").as_os_str().as_bytes(),
+            )?),
+            None => None,
+        };
+        let mut rlimits = Vec::new();
+        if limits.file_bytes > 0 {
+            rlimits.push((libc::RLIMIT_FSIZE, limits.file_bytes));
+        }
+        if cgroup.is_none() {
+            if limits.memory_bytes > 0 {
+                rlimits.push((libc::RLIMIT_DATA, limits.memory_bytes));
+            }
+            if limits.pids > 0 {
+                rlimits.push((libc::RLIMIT_NPROC, limits.pids));
+            }
+        }
+
+        if procs.is_some() || !rlimits.is_empty() {
+            // SAFETY: only open, write and setrlimit on data prepared up front.
+            unsafe {
+                command.pre_exec(move || enter(procs.as_deref(), &rlimits));
+            }
+        }
+
+        Ok(JobLimits { limits, cgroup })
+    }
+}
+
+/// A job's limits, for the lifetime of its build.
+pub struct JobLimits {
+    limits: ResourceLimits,
+    cgroup: Option<PathBuf>,
+}
+
+impl JobLimits {
+    /// The limit a failed build ran into, judged from its cgroup's event counters or,
+    /// under `setrlimit`, from the errors cargo reported.
+    pub fn tripped(&self, stderr: &str) -> Option<Limit> {
+        let limits = self.limits;
+        if let Some(dir) = &self.cgroup {
+            if limits.memory_bytes > 0 && event_count(&dir.join("memory.events"), "oom_kill") > 0 {
+                return Some(Limit::Memory(limits.memory_bytes));
+            }
+            if limits.pids > 0 && event_count(&dir.join("pids.events"), "max") > 0 {
+                return Some(Limit::Pids(limits.pids));
+            }
+        } else {
+            if limits.memory_bytes > 0
+                && (stderr.contains("memory allocation of") || stderr.contains("out of memory"))
+            {
+                return Some(Limit::Memory(limits.memory_bytes));
+            }
+            if limits.pids > 0 && stderr.contains("Resource temporarily unavailable") {
+                return Some(Limit::Pids(limits.pids));
+            }
+        }
+        // Cargo names the signal only on some versions: `(signal: 25, SIGXFSZ: ...)`.
+        if limits.file_bytes > 0
+            && (stderr.contains("SIGXFSZ")
+                || stderr.contains(&format!("(signal: {})", libc::SIGXFSZ)))
+        {
+            return Some(Limit::FileSize(limits.file_bytes));
+        }
+        None
+    }
+
+    /// Total size of the files under `dirs`, the baseline for [`Self::disk_exceeded`].
+    /// Without a disk limit the directories are not walked and this is `0`.
+    pub async fn disk_usage(&self, dirs: &[PathBuf]) -> u64 {
+        if self.limits.disk_bytes == 0 {
+            return 0;
+        }
+        let dirs = dirs.to_vec();
+        tokio::task::spawn_blocking(move || dirs.iter().map(|dir| dir_size(dir)).sum())
+            .await
+            .unwrap_or(0)
+    }
+
+    /// Resolves once the files under `dirs` have grown by more than the disk limit
+    /// since they added up to `baseline`, and never without a limit. Usage is sampled,
+    /// so a build can overshoot the limit by what it writes between two samples.
+    pub async fn disk_exceeded(&self, dirs: Vec<PathBuf>, baseline: u64) -> Limit {
+        let max = self.limits.disk_bytes;
+        if max == 0 {
+            return std::future::pending().await;
+        }
+        let mut interval = tokio::time::interval(DISK_POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            let used = self.disk_usage(&dirs).await;
+            if used.saturating_sub(baseline) > max {
+                return Limit::Disk(max);
+            }
+        }
+    }
+}
+
+impl Drop for JobLimits {
+    fn drop(&mut self) {
+        if let Some(dir) = self.cgroup.take() {
+            tokio::task::spawn_blocking(move || remove_cgroup(&dir));
+        }
+    }
+}
+
+fn remove_cgroup(dir: &Path) {
+    // Anything still running was left behind by the build; kill it so the cgroup
+    // can be removed. `cgroup.kill` needs Linux 5.14.
+    let _ = fs::write(dir.join("cgroup.kill"), "1");
+    for _ in 0..10 {
+        if fs::remove_dir(dir).is_ok() {
+            return;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    warn!(dir = %dir.display(), "Failed to remove cgroup");
+}
+
+/// Runs in the forked child: joins the job's cgroup and sets the rlimits.
+fn enter(procs: Option<&std::ffi::CStr>, rlimits: &[(Resource, u64)]) -> io::Result<()> {
+    // SAFETY: plain syscalls on NUL-terminated strings and buffers owned by the caller.
+    unsafe {
+        if let Some(procs) = procs {
+            let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+            if fd == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            // "0" moves the writing process.
+            let written = libc::write(fd, c"0".as_ptr().cast(), 1);
+            libc::close(fd);
+            if written != 1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        for &(resource, value) in rlimits {
+            let limit = libc::rlimit {
+                rlim_cur: value,
+                rlim_max: value,
+            };
+            if libc::setrlimit(resource, &limit) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Moves the worker into a leaf of its own cgroup, so the cgroup can host per-job
+/// children with the controllers the limits need.
+fn delegate_cgroup() -> io::Result<PathBuf> {
+    let own = fs::read_to_string("/proc/self/cgroup")?
+        .lines()
+        .find_map(|line| line.strip_prefix("0::").map(str::to_string))
+        .ok_or_else(|| io::Error::other("not in a cgroup v2 hierarchy"))?;
+    let mount = cgroup2_mount()?;
+    let dir = mount.join(own.trim_start_matches('/'));
+
+    let available = fs::read_to_string(dir.join("cgroup.controllers"))?;
+    let wanted: Vec<&str> = ["cpu", "memory", "pids"]
+        .into_iter()
+        .filter(|controller| available.split_whitespace().any(|c| c == *controller))
+        .collect();
+    if wanted.is_empty() {
+        return Err(io::Error::other(
+            "no cpu, memory or pids controller delegated",
+        ));
+    }
+
+    // Processes may not live in a cgroup that hands controllers to its children.
+    let leaf = dir.join("worker");
+    fs::create_dir_all(&leaf)?;
+    fs::write(leaf.join("cgroup.procs"), std::process::id().to_string())?;
+    let enable: Vec<String> = wanted.iter().map(|c| format!("+{}", c)).collect();
+    fs::write(dir.join("cgroup.subtree_control"), enable.join(" "))?;
+
+    let jobs = dir.join("jobs");
+    fs::create_dir_all(&jobs)?;
+    fs::write(jobs.join("cgroup.subtree_control"), enable.join(" "))?;
+    Ok(jobs)
+}
+
+fn cgroup2_mount() -> io::Result<PathBuf> {
+    fs::read_to_string("/proc/self/mountinfo")?
+        .lines()
+        .find_map(|line| {
+            let (mount, fs_type) = line.split_once(" - ")?;
+            fs_type
+                .starts_with("cgroup2 ")
+                .then(|| mount.split_whitespace().nth(4).map(PathBuf::from))?
+        })
+        .ok_or_else(|| io::Error::other("cgroup2 is not mounted"))
+}
+
+fn create_job_cgroup(parent: &Path, job_id: &str, limits: &ResourceLimits) -> io::Result<PathBuf> {
+    let dir = parent.join(format!("job-{}", job_id));
+    fs::create_dir(&dir)?;
+    let configure = || -> io::Result<()> {
+        if limits.memory_bytes > 0 {
+            fs::write(dir.join("memory.max"), limits.memory_bytes.to_string())?;
+            // Without this the limit only pushes the build into swap.
+            let _ = fs::write(dir.join("memory.swap.max"), "0");
+        }
+        if limits.pids > 0 {
+            fs::write(dir.join("pids.max"), limits.pids.to_string())?;
+        }
+        if limits.cpus > 0.0 {
+            const PERIOD: u64 = 100_000;
+            let quota = (limits.cpus * PERIOD as f64) as u64;
+            fs::write(
+                dir.join("cpu.max"),
+                format!("{} {}", quota.max(1000), PERIOD),
+            )?;
+        }
+        Ok(())
+    };
+    configure().inspect_err(|_| {
+        let _ = fs::remove_dir(&dir);
+    })?;
+    Ok(dir)
+}
+
+/// Reads one counter from a cgroup `*.events` file.
+fn event_count(path: &Path, key: &str) -> u64 {
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .find_map(|line| {
+            let (name, count) = line.split_once(' ')?;
+            (name == key).then(|| count.trim().parse().ok())?
+        })
+        .unwrap_or(0)
+}
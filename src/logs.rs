@@ -0,0 +1,95 @@
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::broadcast;
+
+/// Lines kept for replay to subscribers that connect after the build started.
+const MAX_BUFFERED_LINES: usize = 10_000;
+
+/// Capacity of the live channel; subscribers falling further behind skip lines.
+const LIVE_CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+    /// A compiler diagnostic as rustc renders it, possibly over several lines.
+    Diagnostic,
+}
+
+impl LogStream {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogStream::Stdout => "stdout",
+            LogStream::Stderr => "stderr",
+            LogStream::Diagnostic => "diagnostic",
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct LogLine {
+    pub stream: LogStream,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    pub line: String,
+}
+
+/// Output of a single build, buffered for replay and fanned out to live subscribers.
+pub struct JobLog {
+    lines: Mutex<VecDeque<LogLine>>,
+    live: Mutex<Option<broadcast::Sender<LogLine>>>,
+}
+
+impl Default for JobLog {
+    fn default() -> JobLog {
+        JobLog {
+            lines: Mutex::new(VecDeque::new()),
+            live: Mutex::new(Some(broadcast::Sender::new(LIVE_CHANNEL_CAPACITY))),
+        }
+    }
+}
+
+impl JobLog {
+    pub fn push(&self, stream: LogStream, line: String) {
+        let line = LogLine {
+            stream,
+            timestamp_ms: now_ms(),
+            line,
+        };
+
+        // Hold the buffer lock while publishing so `subscribe` never sees a line twice or misses one.
+        let mut lines = self.lines.lock().unwrap();
+        if let Some(live) = &*self.live.lock().unwrap() {
+            let _ = live.send(line.clone());
+        }
+        if lines.len() == MAX_BUFFERED_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    /// Marks the log as complete; live subscribers see the channel close once drained.
+    pub fn close(&self) {
+        let _lines = self.lines.lock().unwrap();
+        self.live.lock().unwrap().take();
+    }
+
+    /// Returns the lines buffered so far and, unless the log is closed, a receiver for the rest.
+    pub fn subscribe(&self) -> (Vec<LogLine>, Option<broadcast::Receiver<LogLine>>) {
+        let lines = self.lines.lock().unwrap();
+        let live = self.live.lock().unwrap().as_ref().map(|tx| tx.subscribe());
+        (lines.iter().cloned().collect(), live)
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
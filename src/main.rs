@@ -1,140 +1,273 @@
 use axum::{
-    body::Body,
-    extract::{Query, Request},
-    http::StatusCode,
-    response::Response,
-    routing::post,
-    Router,
+    Router, middleware,
+    routing::{get, post, put},
+};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::Duration,
 };
-use http_body_util::BodyExt;
-use serde::Deserialize;
-use std::{fs, net::SocketAddr, process::Command};
-use tar::Archive;
-use tempfile::tempdir;
 use tokio::net::TcpListener;
+use tracing::{error, info, warn};
+
+mod archive;
+mod auth;
+mod build;
+mod cache;
+mod capabilities;
+mod config;
+mod coordinator;
+mod extract;
+mod handlers;
+mod health;
+mod jobs;
+mod limits;
+mod logs;
+mod messages;
+mod metrics;
+mod params;
+mod queue;
+mod sandbox;
+mod spool;
+mod telemetry;
+mod toolchain;
+mod uploads;
+mod util;
+mod workspaces;
+
+use auth::Auth;
+use cache::BuildCache;
+use capabilities::{Capabilities, Features};
+use config::Config;
+use coordinator::Coordinator;
+use health::Health;
+use jobs::JobStore;
+use limits::Limiter;
+use metrics::Metrics;
+use queue::ExecutorQueue;
+use sandbox::{Sandbox, SandboxMode};
+use toolchain::Toolchain;
+use uploads::Uploads;
+use workspaces::Workspaces;
 
-#[derive(Deserialize)]
-struct CompileParams {
-    crate_name: String,
+pub struct AppState {
+    config: Config,
+    /// Refreshed together with `capabilities`.
+    toolchain: RwLock<Toolchain>,
+    jobs: Arc<JobStore>,
+    uploads: Option<Uploads>,
+    capabilities: RwLock<Capabilities>,
+    health: Health,
+    metrics: Arc<Metrics>,
+    auth: Option<Auth>,
 }
 
 #[tokio::main]
 async fn main() {
-    let app = Router::new().route("/compile", post(compile_handler));
+    telemetry::init();
+    let config = Config::from_env();
 
-    let port = std::env::var("PORT")
-        .ok()
-        .and_then(|p| p.parse::<u16>().ok())
-        .unwrap_or(5000);
+    let toolchain = Toolchain::detect().await;
+    info!(
+        host = %toolchain.host,
+        targets = %toolchain.targets.join(", "),
+        "Detected toolchain"
+    );
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], port));
-    println!("🔧 Worker listening on http://{}", addr);
+    let cache = config.cache_dir.clone().and_then(|dir| {
+        match BuildCache::open(
+            dir.clone(),
+            config.cache_max_bytes,
+            &toolchain.rustc_version,
+        ) {
+            Ok(cache) => {
+                info!(dir = %dir.display(), "Build cache enabled");
+                Some(cache)
+            }
+            Err(e) => {
+                warn!(dir = %dir.display(), error = ?e, "Build cache disabled");
+                None
+            }
+        }
+    });
 
-    axum::serve(TcpListener::bind(addr).await.unwrap(), app)
-        .await
-        .unwrap();
-}
+    std::fs::create_dir_all(config.jobs_dir()).expect("Failed to create jobs directory");
 
-async fn compile_handler(
-    Query(params): Query<CompileParams>,
-    req: Request<Body>
-) -> Response<Body> {
-    println!("📥 Received /compile request for crate: {}", params.crate_name);
+    let workspaces = (config.workspaces_max_bytes > 0).then(|| {
+        Workspaces::new(config.workspaces_dir(), config.workspaces_max_bytes)
+            .inspect_err(|e| warn!(error = ?e, "Persistent workspaces disabled"))
+    });
 
-    let bytes = match req.into_body().collect().await {
-        Ok(collected) => collected.to_bytes(),
-        Err(e) => {
-            eprintln!("❌ Failed to collect request body: {:?}", e);
-            return error_response(StatusCode::BAD_REQUEST, "Failed to collect body");
+    let uploads = (config.blobs_max_bytes > 0).then(|| {
+        Uploads::new(
+            config.blobs_dir(),
+            config.blobs_max_bytes,
+            config.extract_limits,
+        )
+        .inspect_err(|e| warn!(error = ?e, "Delta uploads disabled"))
+    });
+
+    let sandbox = match config.sandbox {
+        SandboxMode::Namespaces => {
+            let sandbox = match Sandbox::new(config.sandbox_root(), config.sandbox_seccomp).await {
+                Ok(sandbox) => sandbox,
+                Err(e) => sandbox_unavailable(e),
+            };
+            if let Err(e) = sandbox.check().await {
+                sandbox_unavailable(e);
+            }
+            for path in config
+                .auth_tokens_file
+                .iter()
+                .chain(&config.auth_hmac_keys_file)
+            {
+                if sandbox.exposes(path) {
+                    error!(
+                        path = %path.display(),
+                        "Authentication keys would be readable by builds; move them out of the directories mounted into the sandbox"
+                    );
+                    std::process::exit(1);
+                }
+            }
+            info!(
+                seccomp = config.sandbox_seccomp,
+                "Builds run in a namespace sandbox"
+            );
+            Some(sandbox)
+        }
+        SandboxMode::None => {
+            warn!("Sandbox disabled, builds run with the worker's privileges");
+            None
         }
     };
 
-    // Create temp directory
-    let temp_dir = match tempdir() {
-        Ok(dir) => dir,
-        Err(_) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, "Temp dir error"),
+    let workspaces = workspaces.and_then(Result::ok);
+    let uploads = uploads.and_then(Result::ok);
+    let features = Features {
+        sandbox: sandbox.is_some(),
+        seccomp: sandbox.is_some() && config.sandbox_seccomp,
+        cache: cache.is_some(),
+        persistent_workspaces: workspaces.is_some(),
+        delta_uploads: uploads.is_some(),
+        upload_compression: vec!["gzip", "zstd", "xz"],
+        artifact_compression: vec!["gzip"],
     };
+    let capabilities = Capabilities::collect(config.work_dir.clone(), features).await;
 
-    // Unpack tarball
-    if let Err(e) = Archive::new(bytes.as_ref()).unpack(&temp_dir) {
-        eprintln!("❌ Failed to unpack archive: {:?}", e);
-        return error_response(StatusCode::BAD_REQUEST, "Unpack failed");
-    }
+    let addr = SocketAddr::new(config.bind_address, config.port);
+    let queue = ExecutorQueue::new(config.executor_slots, config.queue_capacity);
 
-    // Compile specific crate
-    let output = Command::new("cargo")
-        .arg("build")
-        .arg("-p")
-        .arg(&params.crate_name)
-        .arg("--offline")
-        .current_dir(temp_dir.path())
-        .output();
-
-    match output {
-        Ok(o) if o.status.success() => {
-            let target_dir = temp_dir.path().join("target/debug");
-            
-            // First try looking for .rlib (library crates)
-            if let Ok(entries) = fs::read_dir(target_dir.join("deps")) {
-                for entry in entries.flatten() {
-                    let path = entry.path();
-                    if path.extension().map(|ext| ext == "rlib").unwrap_or(false)
-                        && path.file_name().map_or(false, |f| f.to_string_lossy().contains(&format!("lib{}", params.crate_name)))
-                    {
-                        let filename = path.file_name().unwrap().to_string_lossy();
-                        if let Ok(binary) = fs::read(&path) {
-                            return Response::builder()
-                                .status(StatusCode::OK)
-                                .header("Content-Type", "application/octet-stream")
-                                .header("X-Rlib-File", filename.as_ref())
-                                .body(Body::from(binary))
-                                .unwrap();
-                        }
-                    }
-                }
-            }
+    let auth = match Auth::from_config(&config) {
+        Ok(Some(auth)) => {
+            info!(schemes = %auth.schemes().join(", "), "Requests must authenticate");
+            Some(auth)
+        }
+        Ok(None) => {
+            warn!("Authentication disabled, anyone who can reach the worker can run builds");
+            None
+        }
+        Err(e) => {
+            error!(error = %e, "Cannot load authentication keys");
+            std::process::exit(1);
+        }
+    };
 
-            // If no .rlib found, look for executable (binary crates)
-            let exe_path = target_dir.join(&params.crate_name);
-            if exe_path.exists() {
-                if let Ok(binary) = fs::read(&exe_path) {
-                    return Response::builder()
-                        .status(StatusCode::OK)
-                        .header("Content-Type", "application/octet-stream")
-                        .header("X-Binary-File", params.crate_name)
-                        .body(Body::from(binary))
-                        .unwrap();
-                }
-            }
+    let metrics = Arc::new(Metrics::new());
 
-            eprintln!("❌ No output file found for {}", params.crate_name);
-            error_response(StatusCode::INTERNAL_SERVER_ERROR, "No output file found")
-        }
+    let state = Arc::new(AppState {
+        jobs: Arc::new(JobStore::new(
+            cache,
+            workspaces,
+            queue,
+            sandbox,
+            Limiter::new(config.job_limits, config.executor_slots),
+            Arc::clone(&metrics),
+        )),
+        uploads,
+        capabilities: RwLock::new(capabilities),
+        health: Health::new(),
+        metrics,
+        auth,
+        config,
+        toolchain: RwLock::new(toolchain),
+    });
 
-        Ok(o) => {
-            let err = String::from_utf8_lossy(&o.stderr);
-            eprintln!("❌ Compilation failed:\n{}", err);
-            error_response(StatusCode::INTERNAL_SERVER_ERROR, &err)
-        }
+    let app = Router::new()
+        .route("/compile", post(handlers::compile_handler))
+        .route("/jobs", post(handlers::submit_job_handler))
+        .route(
+            "/jobs/:id",
+            get(handlers::job_status_handler).delete(handlers::cancel_job_handler),
+        )
+        .route("/jobs/:id/artifact", get(handlers::job_artifact_handler))
+        .route("/jobs/:id/logs", get(handlers::job_logs_handler))
+        .route("/uploads", post(handlers::upload_manifest_handler))
+        .route("/blobs/:hash", put(handlers::put_blob_handler))
+        .route("/targets", get(handlers::targets_handler))
+        .route("/capabilities", get(handlers::capabilities_handler))
+        .route("/healthz", get(handlers::healthz_handler))
+        .route("/readyz", get(handlers::readyz_handler))
+        .route("/metrics", get(handlers::metrics_handler))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            auth::require_auth,
+        ))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            metrics::track_requests,
+        ))
+        .layer(middleware::from_fn(telemetry::trace_requests))
+        .with_state(Arc::clone(&state));
 
-        Err(e) => {
-            eprintln!("❌ Failed to run cargo: {:?}", e);
-            error_response(StatusCode::INTERNAL_SERVER_ERROR, "Cargo execution failed")
+    let listener = TcpListener::bind(addr).await.unwrap();
+    info!(%addr, "Worker listening");
+
+    tokio::spawn(health::run_self_test(Arc::clone(&state)));
+    tokio::spawn(auth::reload_on_sighup(Arc::clone(&state)));
+
+    let coordinator = Coordinator::from_config(&state.config).map(Arc::new);
+    let heartbeats = coordinator
+        .as_ref()
+        .map(|coordinator| tokio::spawn(Arc::clone(coordinator).run(Arc::clone(&state))));
+
+    let shutdown = {
+        let state = Arc::clone(&state);
+        async move {
+            shutdown_signal().await;
+            state.health.start_draining();
+            if let Some(heartbeats) = heartbeats {
+                heartbeats.abort();
+            }
+            if let Some(coordinator) = coordinator {
+                coordinator.deregister().await;
+            }
+            health::drain(&state, Duration::from_secs(state.config.drain_timeout_secs)).await;
         }
-    }
+    };
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown)
+        .await
+        .unwrap();
+
+    info!("Worker stopped");
 }
 
-fn extract_crate_name(toml: &str) -> String {
-    toml.lines()
-        .find(|line| line.trim_start().starts_with("name ="))
-        .and_then(|line| line.split('=').nth(1))
-        .map(|s| s.trim().trim_matches('"').to_string())
-        .unwrap_or_else(|| "unknown".to_string())
+/// Resolves on Ctrl-C or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("Failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+    info!("Shutting down, draining builds");
 }
 
-fn error_response(status: StatusCode, message: &str) -> Response<Body> {
-    Response::builder()
-        .status(status)
-        .body(Body::from(message.to_string()))
-        .unwrap()
+fn sandbox_unavailable(e: std::io::Error) -> ! {
+    error!(
+        error = %e,
+        "Cannot sandbox builds. Set WORKER_SANDBOX=none if unprivileged user namespaces are disabled on this host"
+    );
+    std::process::exit(1);
 }
@@ -0,0 +1,297 @@
+//! Cargo's `--message-format=json` output and the diagnostics report built from it.
+
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// One line of cargo's JSON output. Only the messages the worker uses are decoded.
+#[derive(Deserialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+pub enum CargoMessage {
+    CompilerMessage {
+        package_id: String,
+        target: CargoTarget,
+        message: RustcDiagnostic,
+    },
+    CompilerArtifact(CompilerArtifact),
+    #[serde(other)]
+    Other,
+}
+
+/// A compilation unit cargo finished, with the exact files it produced.
+#[derive(Deserialize)]
+pub struct CompilerArtifact {
+    pub package_id: String,
+    pub target: CargoTarget,
+    pub filenames: Vec<PathBuf>,
+    pub executable: Option<PathBuf>,
+}
+
+impl CompilerArtifact {
+    pub fn package_name(&self) -> &str {
+        package_name(&self.package_id)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CargoTarget {
+    pub name: String,
+    pub kind: Vec<String>,
+    #[serde(default)]
+    pub crate_types: Vec<String>,
+}
+
+impl CargoTarget {
+    /// Library targets are reported with their crate types (`lib`, `rlib`, `cdylib`, ...).
+    pub fn is_lib(&self) -> bool {
+        self.kind.iter().any(|kind| {
+            matches!(
+                kind.as_str(),
+                "lib" | "rlib" | "dylib" | "cdylib" | "staticlib" | "proc-macro"
+            )
+        })
+    }
+
+    pub fn is_build_script(&self) -> bool {
+        self.kind.iter().any(|kind| kind == "custom-build")
+    }
+}
+
+/// Every `compiler-artifact` message in cargo's stdout.
+pub fn compiler_artifacts(stdout: &str) -> impl Iterator<Item = CompilerArtifact> + '_ {
+    stdout
+        .lines()
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(CargoMessage::CompilerArtifact(artifact)) => Some(artifact),
+            _ => None,
+        })
+}
+
+/// Extracts the package name from a package ID such as `path+file:///ws/my-app#0.1.0`
+/// or `registry+https://github.com/rust-lang/crates.io-index#serde@1.0.219`.
+///
+/// Also accepts the `name version (source)` form used by cargo before 1.77.
+pub fn package_name(package_id: &str) -> &str {
+    let Some((url, fragment)) = package_id.rsplit_once('#') else {
+        return package_id.split(' ').next().unwrap_or(package_id);
+    };
+
+    match fragment.split_once('@') {
+        Some((name, _version)) => name,
+        // Without an explicit name the package is named after the last path segment.
+        None => {
+            let path = url.split('?').next().unwrap_or(url).trim_end_matches('/');
+            path.rsplit('/').next().unwrap_or(path)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RustcDiagnostic {
+    pub message: String,
+    pub code: Option<RustcCode>,
+    pub level: String,
+    pub spans: Vec<Span>,
+    pub rendered: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RustcCode {
+    pub code: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Span {
+    pub file_name: String,
+    pub line_start: usize,
+    pub line_end: usize,
+    pub column_start: usize,
+    pub column_end: usize,
+    pub is_primary: bool,
+    pub label: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Diagnostic {
+    pub package_id: String,
+    pub target: String,
+    pub level: String,
+    pub code: Option<String>,
+    pub message: String,
+    pub spans: Vec<Span>,
+    pub rendered: Option<String>,
+}
+
+#[derive(Default, Serialize)]
+pub struct DiagnosticSummary {
+    pub errors: usize,
+    pub warnings: usize,
+}
+
+/// JSON body returned for a failed build.
+#[derive(Serialize)]
+pub struct DiagnosticReport {
+    pub summary: DiagnosticSummary,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Cargo's own stderr, which carries failures rustc does not report (manifest
+    /// errors, failing build scripts, ...).
+    pub stderr: String,
+}
+
+impl DiagnosticReport {
+    pub fn from_output(stdout: &str, stderr: String) -> DiagnosticReport {
+        let mut summary = DiagnosticSummary::default();
+        let mut diagnostics = Vec::new();
+
+        for message in stdout
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+        {
+            let CargoMessage::CompilerMessage {
+                package_id,
+                target,
+                message,
+            } = message
+            else {
+                continue;
+            };
+
+            match message.level.as_str() {
+                "error" | "error: internal compiler error" => summary.errors += 1,
+                "warning" => summary.warnings += 1,
+                _ => {}
+            }
+
+            diagnostics.push(Diagnostic {
+                package_id,
+                target: target.name,
+                level: message.level,
+                code: message.code.map(|c| c.code),
+                message: message.message,
+                spans: message.spans,
+                rendered: message.rendered,
+            });
+        }
+
+        DiagnosticReport {
+            summary,
+            diagnostics,
+            stderr,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn package_name_from_legacy_ids() {
+        assert_eq!(
+            package_name("my-app 0.1.0 (path+file:///ws/my-app)"),
+            "my-app"
+        );
+        assert_eq!(
+            package_name("serde 1.0.219 (registry+https://github.com/rust-lang/crates.io-index)"),
+            "serde"
+        );
+    }
+
+    #[test]
+    fn package_name_from_path_named_ids() {
+        assert_eq!(package_name("path+file:///ws/my-app#0.1.0"), "my-app");
+        assert_eq!(package_name("path+file:///ws/my-app/#0.1.0"), "my-app");
+        assert_eq!(
+            package_name("git+https://github.com/org/tool?branch=main#1.2.0"),
+            "tool"
+        );
+    }
+
+    #[test]
+    fn package_name_from_explicitly_named_ids() {
+        assert_eq!(
+            package_name("registry+https://github.com/rust-lang/crates.io-index#serde@1.0.219"),
+            "serde"
+        );
+        assert_eq!(
+            package_name("path+file:///ws/crates/core#my-core@0.1.0"),
+            "my-core"
+        );
+        assert_eq!(
+            package_name("git+https://github.com/org/repo?rev=abc123#macros@0.3.0"),
+            "macros"
+        );
+    }
+
+    #[test]
+    fn finds_compiler_artifacts_among_other_messages() {
+        let stdout = concat!(
+            r#"{"reason":"compiler-artifact","package_id":"path+file:///ws/app#0.1.0","#,
+            r#""target":{"name":"app","kind":["lib"],"crate_types":["lib"]},"#,
+            r#""filenames":["/t/debug/libapp.rlib","/t/debug/libapp.rmeta"],"executable":null}"#,
+            "\n",
+            r#"{"reason":"build-script-executed","package_id":"path+file:///ws/app#0.1.0"}"#,
+            "\n",
+            "not json\n",
+            r#"{"reason":"compiler-artifact","package_id":"path+file:///ws/app#0.1.0","#,
+            r#""target":{"name":"app","kind":["bin"],"crate_types":["bin"]},"#,
+            r#""filenames":["/t/debug/app"],"executable":"/t/debug/app"}"#,
+            "\n",
+            r#"{"reason":"build-finished","success":true}"#,
+        );
+
+        let artifacts: Vec<CompilerArtifact> = compiler_artifacts(stdout).collect();
+        assert_eq!(artifacts.len(), 2);
+        assert_eq!(artifacts[0].package_name(), "app");
+        assert!(artifacts[0].target.is_lib());
+        assert_eq!(artifacts[0].filenames.len(), 2);
+        assert!(!artifacts[1].target.is_lib());
+        assert_eq!(
+            artifacts[1].executable.as_deref(),
+            Some(std::path::Path::new("/t/debug/app"))
+        );
+    }
+
+    #[test]
+    fn reports_diagnostics_among_other_messages() {
+        let stdout = concat!(
+            r#"{"reason":"compiler-message","package_id":"path+file:///ws/app#0.1.0","#,
+            r#""target":{"name":"app","kind":["lib"],"crate_types":["lib"]},"#,
+            r#""message":{"message":"unused variable: `x`","code":{"code":"unused_variables"},"#,
+            r#""level":"warning","spans":[{"file_name":"src/lib.rs","line_start":2,"#,
+            r#""line_end":2,"column_start":9,"column_end":10,"is_primary":true,"label":null}],"#,
+            r#""rendered":"warning: unused variable: `x`"}}"#,
+            "\n",
+            r#"{"reason":"compiler-artifact","package_id":"path+file:///ws/app#0.1.0","#,
+            r#""target":{"name":"app","kind":["lib"],"crate_types":["lib"]},"#,
+            r#""filenames":["/t/debug/libapp.rlib"],"executable":null}"#,
+            "\n",
+            "not json\n",
+            r#"{"reason":"compiler-message","package_id":"path+file:///ws/app#0.1.0","#,
+            r#""target":{"name":"app","kind":["bin"],"crate_types":["bin"]},"#,
+            r#""message":{"message":"mismatched types","code":{"code":"E0308"},"#,
+            r#""level":"error","spans":[],"rendered":"error[E0308]: mismatched types"}}"#,
+            "\n",
+            r#"{"reason":"compiler-message","package_id":"path+file:///ws/app#0.1.0","#,
+            r#""target":{"name":"app","kind":["bin"],"crate_types":["bin"]},"#,
+            r#""message":{"message":"aborting due to 1 previous error","code":null,"#,
+            r#""level":"error","spans":[],"rendered":null}}"#,
+            "\n",
+            r#"{"reason":"build-finished","success":false}"#,
+        );
+
+        let report = DiagnosticReport::from_output(stdout, "error: could not compile".into());
+        assert_eq!(report.summary.errors, 2);
+        assert_eq!(report.summary.warnings, 1);
+        assert_eq!(report.diagnostics.len(), 3);
+        assert_eq!(report.diagnostics[0].target, "app");
+        assert_eq!(
+            report.diagnostics[0].code.as_deref(),
+            Some("unused_variables")
+        );
+        assert_eq!(report.diagnostics[0].spans[0].line_start, 2);
+        assert_eq!(report.diagnostics[1].level, "error");
+        assert_eq!(report.diagnostics[1].code.as_deref(), Some("E0308"));
+        assert_eq!(report.diagnostics[2].code, None);
+        assert_eq!(report.stderr, "error: could not compile");
+    }
+}
@@ -0,0 +1,201 @@
+//! Prometheus metrics, exposed as text at `/metrics`.
+
+use axum::{
+    body::Body,
+    extract::{MatchedPath, Request, State},
+    http::Response,
+    middleware::Next,
+};
+use hyper::body::Body as _;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder, exponential_buckets,
+};
+use std::{sync::Arc, time::Duration};
+
+use crate::AppState;
+use crate::capabilities::disk_space;
+use crate::jobs::JobState;
+use crate::util::dir_size;
+
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    response_size: HistogramVec,
+    upload_size: Histogram,
+    unpack_duration: Histogram,
+    build_duration: Histogram,
+    builds: IntCounterVec,
+    cache_hits: IntCounter,
+    cache_misses: IntCounter,
+    queued_jobs: IntGauge,
+    running_jobs: IntGauge,
+    executor_slots: IntGauge,
+    temp_dir_bytes: IntGauge,
+    work_dir_free_bytes: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let byte_buckets = exponential_buckets(1024.0, 4.0, 12).unwrap();
+        let metrics = Metrics {
+            registry: Registry::new_custom(Some("distbuild".to_string()), None).unwrap(),
+            http_requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests by route and status"),
+                &["method", "route", "status"],
+            )
+            .unwrap(),
+            response_size: HistogramVec::new(
+                HistogramOpts::new("http_response_size_bytes", "Size of response bodies")
+                    .buckets(byte_buckets.clone()),
+                &["route"],
+            )
+            .unwrap(),
+            upload_size: Histogram::with_opts(
+                HistogramOpts::new("upload_size_bytes", "Size of uploaded workspace archives")
+                    .buckets(byte_buckets),
+            )
+            .unwrap(),
+            unpack_duration: Histogram::with_opts(
+                HistogramOpts::new(
+                    "unpack_duration_seconds",
+                    "Time spent unpacking uploaded workspaces",
+                )
+                .buckets(exponential_buckets(0.01, 2.0, 12).unwrap()),
+            )
+            .unwrap(),
+            build_duration: Histogram::with_opts(
+                HistogramOpts::new("build_duration_seconds", "Time cargo ran for a build").buckets(
+                    vec![
+                        1.0, 2.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0, 1800.0,
+                        3600.0,
+                    ],
+                ),
+            )
+            .unwrap(),
+            builds: IntCounterVec::new(
+                Opts::new("builds_total", "Uncached builds by final state"),
+                &["outcome"],
+            )
+            .unwrap(),
+            cache_hits: IntCounter::new("cache_hits_total", "Builds served from the cache")
+                .unwrap(),
+            cache_misses: IntCounter::new("cache_misses_total", "Builds not found in the cache")
+                .unwrap(),
+            queued_jobs: IntGauge::new("queued_jobs", "Builds waiting for an executor slot")
+                .unwrap(),
+            running_jobs: IntGauge::new("running_jobs", "Builds holding an executor slot").unwrap(),
+            executor_slots: IntGauge::new("executor_slots", "Builds allowed to run at once")
+                .unwrap(),
+            temp_dir_bytes: IntGauge::new(
+                "temp_dir_bytes",
+                "Disk used by uploads and workspaces of in-flight jobs",
+            )
+            .unwrap(),
+            work_dir_free_bytes: IntGauge::new(
+                "work_dir_free_bytes",
+                "Free space on the work directory's filesystem",
+            )
+            .unwrap(),
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 13] = [
+            Box::new(metrics.http_requests.clone()),
+            Box::new(metrics.response_size.clone()),
+            Box::new(metrics.upload_size.clone()),
+            Box::new(metrics.unpack_duration.clone()),
+            Box::new(metrics.build_duration.clone()),
+            Box::new(metrics.builds.clone()),
+            Box::new(metrics.cache_hits.clone()),
+            Box::new(metrics.cache_misses.clone()),
+            Box::new(metrics.queued_jobs.clone()),
+            Box::new(metrics.running_jobs.clone()),
+            Box::new(metrics.executor_slots.clone()),
+            Box::new(metrics.temp_dir_bytes.clone()),
+            Box::new(metrics.work_dir_free_bytes.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).unwrap();
+        }
+        metrics
+    }
+
+    pub fn observe_upload(&self, bytes: u64, unpack: Duration) {
+        self.upload_size.observe(bytes as f64);
+        self.unpack_duration.observe(unpack.as_secs_f64());
+    }
+
+    pub fn observe_build(&self, duration: Duration) {
+        self.build_duration.observe(duration.as_secs_f64());
+    }
+
+    pub fn count_build(&self, outcome: JobState) {
+        self.builds.with_label_values(&[outcome.name()]).inc();
+    }
+
+    pub fn observe_cache_lookup(&self, hit: bool) {
+        if hit {
+            self.cache_hits.inc();
+        } else {
+            self.cache_misses.inc();
+        }
+    }
+
+    /// Refreshes the gauges and renders every metric in the text exposition format.
+    pub async fn render(&self, state: &AppState) -> String {
+        let load = state.jobs.load();
+        self.queued_jobs.set(load.queued as i64);
+        self.running_jobs.set(load.running as i64);
+        self.executor_slots.set(load.executor_slots as i64);
+
+        // Walking the job directories reads every file's metadata.
+        let jobs_dir = state.config.jobs_dir();
+        let work_dir = state.config.work_dir.clone();
+        let usage = tokio::task::spawn_blocking(move || {
+            let free = disk_space(&work_dir).map_or(0, |(_, free)| free);
+            (dir_size(&jobs_dir), free)
+        })
+        .await;
+        if let Ok((temp, free)) = usage {
+            self.temp_dir_bytes.set(temp as i64);
+            self.work_dir_free_bytes.set(free as i64);
+        }
+
+        let mut text = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut text)
+            .unwrap();
+        String::from_utf8(text).unwrap()
+    }
+}
+
+/// Counts every request by route and status, and records the response size when
+/// it is known up front.
+pub async fn track_requests(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Response<Body> {
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", MatchedPath::as_str)
+        .to_string();
+
+    let response = next.run(req).await;
+
+    let metrics = &state.metrics;
+    metrics
+        .http_requests
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .inc();
+    // Streams such as job logs have no size until they end.
+    if let Some(size) = response.body().size_hint().exact() {
+        metrics
+            .response_size
+            .with_label_values(&[&route])
+            .observe(size as f64);
+    }
+    response
+}
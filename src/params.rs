@@ -0,0 +1,300 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Deserialize)]
+pub struct CompileParams {
+    pub crate_name: String,
+    /// Cargo profile to build with (`dev`, `release` or any custom `[profile.*]`).
+    #[serde(default = "default_profile")]
+    pub profile: String,
+    /// Comma or space separated list of features, as accepted by `cargo --features`.
+    #[serde(default)]
+    pub features: String,
+    #[serde(default)]
+    pub no_default_features: bool,
+    #[serde(default)]
+    pub all_features: bool,
+    #[serde(default)]
+    pub locked: bool,
+    #[serde(default)]
+    pub frozen: bool,
+    /// Target triple to cross-compile for; the host target when absent.
+    pub target: Option<String>,
+    #[serde(default)]
+    pub output: OutputMode,
+    /// Compression applied to the response archive; only valid with `output=archive`.
+    #[serde(default)]
+    pub compression: Compression,
+    /// Opts into a persistent target directory shared by all builds with this key.
+    pub workspace_key: Option<String>,
+    /// How long cargo may run before it is killed, capped by the worker's maximum.
+    pub timeout_secs: Option<u64>,
+}
+
+/// Shape of a successful build's response body.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputMode {
+    /// The package's primary output file (rlib, library or binary).
+    #[default]
+    File,
+    /// A tar of every file the package produced, with a `manifest.json`.
+    Archive,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+}
+
+fn default_profile() -> String {
+    "dev".to_string()
+}
+
+impl CompileParams {
+    pub fn validate(&self) -> Result<(), String> {
+        if !is_valid_profile_name(&self.profile) {
+            return Err(format!("Invalid profile name: {}", self.profile));
+        }
+        if let Some(feature) = self
+            .feature_list()
+            .into_iter()
+            .find(|f| !is_valid_feature_name(f))
+        {
+            return Err(format!("Invalid feature name: {}", feature));
+        }
+        if let Some(target) = &self.target
+            && !is_valid_target_triple(target)
+        {
+            return Err(format!("Invalid target triple: {}", target));
+        }
+        if self.compression != Compression::None && self.output != OutputMode::Archive {
+            return Err("Compression requires output=archive".to_string());
+        }
+        if let Some(key) = &self.workspace_key
+            && (key.is_empty() || key.len() > 128 || key.chars().any(|c| c.is_control()))
+        {
+            return Err("Invalid workspace key".to_string());
+        }
+        if self.timeout_secs == Some(0) {
+            return Err("timeout_secs must be positive".to_string());
+        }
+        Ok(())
+    }
+
+    /// Requested features, sorted and deduplicated so equivalent requests compare equal.
+    pub fn feature_list(&self) -> Vec<&str> {
+        let mut features: Vec<&str> = self
+            .features
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|f| !f.is_empty())
+            .collect();
+        features.sort_unstable();
+        features.dedup();
+        features
+    }
+
+    /// Arguments passed to `cargo build` for these parameters.
+    ///
+    /// Everything that influences the produced artifacts goes through here, so the
+    /// result doubles as the parameter part of a cache key.
+    pub fn cargo_args(&self) -> Vec<String> {
+        let mut args = vec![
+            "build".to_string(),
+            "-p".to_string(),
+            self.crate_name.clone(),
+            "--profile".to_string(),
+            self.profile.clone(),
+            "--offline".to_string(),
+        ];
+
+        let features = self.feature_list();
+        if !features.is_empty() {
+            args.push("--features".to_string());
+            args.push(features.join(","));
+        }
+        if self.no_default_features {
+            args.push("--no-default-features".to_string());
+        }
+        if self.all_features {
+            args.push("--all-features".to_string());
+        }
+        if self.frozen {
+            args.push("--frozen".to_string());
+        } else if self.locked {
+            args.push("--locked".to_string());
+        }
+        if let Some(target) = &self.target {
+            args.push("--target".to_string());
+            args.push(target.clone());
+        }
+
+        args
+    }
+}
+
+fn is_valid_profile_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn is_valid_target_triple(triple: &str) -> bool {
+    !triple.is_empty()
+        && !triple.starts_with('.')
+        && triple
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Accepts plain features (`serde`) as well as dependency features (`dep/feat`, `dep?/feat`).
+fn is_valid_feature_name(name: &str) -> bool {
+    let (dep, feature) = match name.split_once('/') {
+        Some((dep, feature)) => (Some(dep.strip_suffix('?').unwrap_or(dep)), feature),
+        None => (None, name),
+    };
+    let valid_part = |part: &str| {
+        !part.is_empty()
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '+' | '.'))
+    };
+    dep.is_none_or(valid_part) && valid_part(feature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn params(mut query: serde_json::Value) -> CompileParams {
+        query["crate_name"] = json!("app");
+        serde_json::from_value(query).unwrap()
+    }
+
+    #[test]
+    fn accepts_plain_and_dependency_features() {
+        for name in ["serde", "std", "dep/feat", "dep?/feat", "full-1.0_x+y"] {
+            assert!(is_valid_feature_name(name), "{name}");
+        }
+        for name in [
+            "",
+            "a b",
+            "x=y",
+            "/feat",
+            "dep/",
+            "a/b/c",
+            "dep??/feat",
+            "$(x)",
+        ] {
+            assert!(!is_valid_feature_name(name), "{name}");
+        }
+    }
+
+    #[test]
+    fn normalizes_the_feature_list() {
+        let sorted = params(json!({ "features": "b, a  b,serde/std" }));
+        assert_eq!(sorted.feature_list(), ["a", "b", "serde/std"]);
+        assert!(sorted.validate().is_ok());
+
+        let invalid = params(json!({ "features": "a,x=y" }));
+        assert_eq!(invalid.validate().unwrap_err(), "Invalid feature name: x=y");
+    }
+
+    #[test]
+    fn validates_profile_names() {
+        assert_eq!(params(json!({})).profile, "dev");
+        for profile in ["dev", "release", "bench-fast", "ci_profile"] {
+            assert!(params(json!({ "profile": profile })).validate().is_ok());
+        }
+        for profile in ["", "../release", "release --offline", "a.b"] {
+            assert_eq!(
+                params(json!({ "profile": profile }))
+                    .validate()
+                    .unwrap_err(),
+                format!("Invalid profile name: {}", profile)
+            );
+        }
+    }
+
+    #[test]
+    fn validates_target_triples() {
+        for target in [
+            "x86_64-unknown-linux-gnu",
+            "wasm32-unknown-unknown",
+            "thumbv7em-none-eabihf",
+            "aarch64-apple-ios-sim",
+            "x86_64-unknown-linux-gnu.json",
+        ] {
+            assert!(
+                params(json!({ "target": target })).validate().is_ok(),
+                "{target}"
+            );
+        }
+        for target in [
+            "",
+            ".hidden",
+            "../x86_64",
+            "/etc/target.json",
+            "a b",
+            "x86_64;ls",
+        ] {
+            assert_eq!(
+                params(json!({ "target": target })).validate().unwrap_err(),
+                format!("Invalid target triple: {}", target)
+            );
+        }
+    }
+
+    #[test]
+    fn passes_the_target_to_cargo() {
+        let cross = params(json!({ "target": "wasm32-unknown-unknown" }));
+        assert!(
+            cross
+                .cargo_args()
+                .ends_with(&["--target".to_string(), "wasm32-unknown-unknown".to_string()])
+        );
+        assert!(
+            !params(json!({}))
+                .cargo_args()
+                .contains(&"--target".to_string())
+        );
+    }
+
+    #[test]
+    fn passes_build_flags_to_cargo() {
+        let all = params(json!({
+            "profile": "release",
+            "features": "b,a",
+            "no_default_features": true,
+            "all_features": true,
+            "locked": true,
+            "frozen": true,
+        }));
+        assert_eq!(
+            all.cargo_args(),
+            [
+                "build",
+                "-p",
+                "app",
+                "--profile",
+                "release",
+                "--offline",
+                "--features",
+                "a,b",
+                "--no-default-features",
+                "--all-features",
+                "--frozen",
+            ]
+        );
+
+        let locked = params(json!({ "locked": true }));
+        assert_eq!(
+            locked.cargo_args()[5..],
+            ["--offline".to_string(), "--locked".to_string()]
+        );
+    }
+}
@@ -0,0 +1,284 @@
+//! Admission control for builds: a fixed number of executor slots and a bounded
+//! FIFO of jobs waiting for one.
+
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    mem,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::oneshot;
+
+pub struct ExecutorQueue {
+    slots: usize,
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    running: usize,
+    waiting: VecDeque<Waiter>,
+}
+
+struct Waiter {
+    job_id: String,
+    wake: oneshot::Sender<()>,
+}
+
+#[derive(Clone, Copy, Serialize)]
+pub struct QueueLoad {
+    pub running: usize,
+    pub queued: usize,
+    pub executor_slots: usize,
+    pub queue_capacity: usize,
+}
+
+/// Returned when every slot is busy and the queue is at capacity.
+pub struct QueueFull;
+
+/// A job's place in line, handed out by [`ExecutorQueue::enqueue`].
+///
+/// Dropping a ticket before it is turned into a [`Slot`] gives up the place (or the
+/// slot, if one was already handed over).
+pub struct Ticket {
+    queue: Arc<ExecutorQueue>,
+    job_id: String,
+    state: TicketState,
+}
+
+enum TicketState {
+    Granted,
+    Waiting(oneshot::Receiver<()>),
+    Taken,
+}
+
+/// A claimed executor slot, released to the next waiting job on drop.
+pub struct Slot {
+    queue: Arc<ExecutorQueue>,
+}
+
+impl ExecutorQueue {
+    pub fn new(slots: usize, capacity: usize) -> ExecutorQueue {
+        ExecutorQueue {
+            slots: slots.max(1),
+            capacity,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Takes a free slot right away, or a place at the back of the queue.
+    pub fn enqueue(self: &Arc<Self>, job_id: &str) -> Result<Ticket, QueueFull> {
+        let mut inner = self.inner.lock().unwrap();
+        let state = if inner.running < self.slots {
+            inner.running += 1;
+            TicketState::Granted
+        } else if inner.waiting.len() < self.capacity {
+            let (wake, rx) = oneshot::channel();
+            inner.waiting.push_back(Waiter {
+                job_id: job_id.to_string(),
+                wake,
+            });
+            TicketState::Waiting(rx)
+        } else {
+            return Err(QueueFull);
+        };
+
+        Ok(Ticket {
+            queue: Arc::clone(self),
+            job_id: job_id.to_string(),
+            state,
+        })
+    }
+
+    /// Builds currently holding a slot and builds waiting for one.
+    pub fn load(&self) -> QueueLoad {
+        let inner = self.inner.lock().unwrap();
+        QueueLoad {
+            running: inner.running,
+            queued: inner.waiting.len(),
+            executor_slots: self.slots,
+            queue_capacity: self.capacity,
+        }
+    }
+
+    /// Whether a new job would be turned away right now.
+    pub fn is_full(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.running >= self.slots && inner.waiting.len() >= self.capacity
+    }
+
+    /// 1-based position of a job among those waiting for a slot.
+    pub fn position(&self, job_id: &str) -> Option<usize> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .waiting
+            .iter()
+            .position(|waiter| waiter.job_id == job_id)
+            .map(|index| index + 1)
+    }
+
+    /// Hands a finished job's slot to the first waiter still interested in it.
+    fn release(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        while let Some(waiter) = inner.waiting.pop_front() {
+            if waiter.wake.send(()).is_ok() {
+                return;
+            }
+        }
+        inner.running -= 1;
+    }
+
+    /// Removes a job from the queue. Returns `false` if it was already woken.
+    fn leave(&self, job_id: &str) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner
+            .waiting
+            .iter()
+            .position(|waiter| waiter.job_id == job_id)
+        {
+            Some(index) => {
+                inner.waiting.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Ticket {
+    /// Waits until the job reaches the front of the queue and a slot frees up.
+    pub async fn acquire(mut self) -> Slot {
+        if let TicketState::Waiting(rx) = &mut self.state {
+            let _ = rx.await;
+        }
+        self.state = TicketState::Taken;
+        Slot {
+            queue: Arc::clone(&self.queue),
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        match mem::replace(&mut self.state, TicketState::Taken) {
+            TicketState::Granted => self.queue.release(),
+            TicketState::Waiting(mut rx) => {
+                // A waiter is only missing from the queue once `release` has woken
+                // it, so the slot it was handed must be passed on.
+                if !self.queue.leave(&self.job_id) && rx.try_recv().is_ok() {
+                    self.queue.release();
+                }
+            }
+            TicketState::Taken => {}
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.queue.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn queue(slots: usize, capacity: usize) -> Arc<ExecutorQueue> {
+        Arc::new(ExecutorQueue::new(slots, capacity))
+    }
+
+    fn enqueue(queue: &Arc<ExecutorQueue>, job_id: &str) -> Ticket {
+        match queue.enqueue(job_id) {
+            Ok(ticket) => ticket,
+            Err(QueueFull) => panic!("queue is full"),
+        }
+    }
+
+    /// Whether the ticket gets a slot without waiting.
+    async fn acquire_now(ticket: Ticket) -> Option<Slot> {
+        tokio::time::timeout(Duration::from_millis(50), ticket.acquire())
+            .await
+            .ok()
+    }
+
+    fn counts(queue: &ExecutorQueue) -> (usize, usize) {
+        let load = queue.load();
+        (load.running, load.queued)
+    }
+
+    #[tokio::test]
+    async fn hands_slots_out_in_order() {
+        let queue = queue(1, 2);
+        let a = enqueue(&queue, "a");
+        let b = enqueue(&queue, "b");
+        let c = enqueue(&queue, "c");
+        assert!(queue.is_full());
+        assert!(queue.enqueue("d").is_err());
+        assert_eq!(queue.position("b"), Some(1));
+        assert_eq!(queue.position("c"), Some(2));
+        assert_eq!(queue.position("a"), None);
+
+        let a = acquire_now(a).await.unwrap();
+        drop(a);
+        assert_eq!(counts(&queue), (1, 1));
+        assert_eq!(queue.position("c"), Some(1));
+        let b = acquire_now(b).await.unwrap();
+        assert!(acquire_now(enqueue(&queue, "e")).await.is_none());
+        drop(b);
+        let c = acquire_now(c).await.unwrap();
+        drop(c);
+        assert_eq!(counts(&queue), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn dropping_a_waiting_ticket_leaves_the_queue() {
+        let queue = queue(1, 2);
+        let a = enqueue(&queue, "a");
+        let b = enqueue(&queue, "b");
+        let c = enqueue(&queue, "c");
+
+        drop(b);
+        assert_eq!(counts(&queue), (1, 1));
+        assert_eq!(queue.position("c"), Some(1));
+
+        drop(a);
+        assert!(acquire_now(c).await.is_some());
+        assert_eq!(counts(&queue), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn a_slot_handed_to_a_dropped_ticket_is_passed_on() {
+        let queue = queue(1, 2);
+        let a = enqueue(&queue, "a");
+        let b = enqueue(&queue, "b");
+        let c = enqueue(&queue, "c");
+
+        // `b` is woken with the slot, then goes away before claiming it.
+        drop(acquire_now(a).await.unwrap());
+        assert_eq!(counts(&queue), (1, 1));
+        drop(b);
+        let c = acquire_now(c).await.unwrap();
+        assert_eq!(counts(&queue), (1, 0));
+
+        drop(c);
+        assert_eq!(counts(&queue), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn a_granted_ticket_returns_its_slot_when_dropped() {
+        let queue = queue(2, 0);
+        let a = enqueue(&queue, "a");
+        let b = enqueue(&queue, "b");
+        assert!(queue.enqueue("c").is_err());
+
+        drop(a);
+        assert_eq!(counts(&queue), (1, 0));
+        let c = enqueue(&queue, "c");
+        assert!(acquire_now(b).await.is_some());
+        assert!(acquire_now(c).await.is_some());
+        assert_eq!(counts(&queue), (0, 0));
+    }
+}
@@ -0,0 +1,611 @@
+//! Runs cargo, and with it every build script and proc-macro, in unprivileged Linux
+//! namespaces.
+//!
+//! Each build gets new user, mount, PID, network, IPC and UTS namespaces. Its root
+//! filesystem is a read-only tmpfs holding read-only binds of the system directories,
+//! the parts of `/etc` needed to run compilers and linkers, and the Rust toolchain
+//! without cargo's credentials or configuration, a private `/tmp`, `/proc` for its own processes, a few
+//! device nodes, and the workspace and target directories as the only writable host
+//! paths. The network namespace has no interfaces besides a down loopback, so builds
+//! have no network access. An optional seccomp filter additionally denies syscalls a
+//! build has no business making.
+
+use std::{
+    collections::HashSet,
+    ffi::CString,
+    fs, io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tokio::process::Command;
+use tracing::warn;
+
+/// System paths builds may read. Missing ones are skipped.
+const SYSTEM_PATHS: &[&str] = &[
+    "/usr",
+    "/bin",
+    "/sbin",
+    "/lib",
+    "/lib32",
+    "/lib64",
+    "/libx32",
+    "/etc/alternatives",
+    "/etc/group",
+    "/etc/hosts",
+    "/etc/ld.so.cache",
+    "/etc/ld.so.conf",
+    "/etc/ld.so.conf.d",
+    "/etc/localtime",
+    "/etc/nsswitch.conf",
+    "/etc/passwd",
+];
+
+/// Parts of `CARGO_HOME` builds need: the toolchain proxies and downloaded
+/// dependencies. Credentials and configuration stay hidden.
+const CARGO_HOME_DIRS: &[&str] = &["bin", "registry", "git"];
+
+const DEVICES: &[&str] = &["null", "zero", "full", "random", "urandom", "tty"];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SandboxMode {
+    /// Builds run in fresh namespaces; requires unprivileged user namespaces.
+    Namespaces,
+    /// Builds run with the worker's own privileges.
+    None,
+}
+
+impl FromStr for SandboxMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<SandboxMode, ()> {
+        match s {
+            "namespaces" => Ok(SandboxMode::Namespaces),
+            "none" => Ok(SandboxMode::None),
+            _ => Err(()),
+        }
+    }
+}
+
+pub struct Sandbox {
+    /// Empty host directory the sandbox's root filesystem is mounted on.
+    root: PathBuf,
+    /// Host paths visible read-only inside the sandbox.
+    read_only: Vec<PathBuf>,
+    seccomp: bool,
+}
+
+impl Sandbox {
+    /// Prepares a sandbox exposing the system directories and the Rust toolchain.
+    pub async fn new(root: PathBuf, seccomp: bool) -> io::Result<Sandbox> {
+        fs::create_dir_all(&root)?;
+
+        let home = std::env::var_os("HOME").map(PathBuf::from);
+        let cargo_home = std::env::var_os("CARGO_HOME")
+            .map(PathBuf::from)
+            .or_else(|| home.as_ref().map(|home| home.join(".cargo")));
+        let rustup_home = std::env::var_os("RUSTUP_HOME")
+            .map(PathBuf::from)
+            .or_else(|| home.as_ref().map(|home| home.join(".rustup")));
+
+        let mut read_only: Vec<PathBuf> = SYSTEM_PATHS.iter().map(PathBuf::from).collect();
+        if let Some(cargo_home) = cargo_home {
+            read_only.extend(CARGO_HOME_DIRS.iter().map(|dir| cargo_home.join(dir)));
+        }
+        read_only.extend(rustup_home);
+        read_only.extend(sysroot().await);
+
+        // Nested paths are already visible through their ancestor.
+        read_only.retain(|path| path.is_absolute() && fs::symlink_metadata(path).is_ok());
+        read_only.sort();
+        read_only.dedup();
+        let nested: HashSet<PathBuf> = read_only
+            .iter()
+            .filter(|path| {
+                read_only
+                    .iter()
+                    .any(|other| other != *path && path.starts_with(other) && !other.is_symlink())
+            })
+            .cloned()
+            .collect();
+        read_only.retain(|path| !nested.contains(path));
+
+        if seccomp && seccomp_filter().is_none() {
+            warn!("Seccomp filtering is not supported on this architecture");
+        }
+
+        Ok(Sandbox {
+            root,
+            read_only,
+            seccomp,
+        })
+    }
+
+    /// Whether builds can read `path` through one of the read-only mounts.
+    pub fn exposes(&self, path: &Path) -> bool {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        self.read_only.iter().any(|mounted| {
+            path.starts_with(fs::canonicalize(mounted).unwrap_or_else(|_| mounted.clone()))
+        })
+    }
+
+    /// Makes `command` run inside the sandbox, starting in `workdir`.
+    ///
+    /// `writable` paths are created if needed and mounted read-write at the same
+    /// location, so paths cargo reports stay valid on the host.
+    pub fn apply(
+        &self,
+        command: &mut Command,
+        workdir: &Path,
+        writable: &[&Path],
+    ) -> io::Result<()> {
+        let mut writable_paths = Vec::new();
+        for path in writable {
+            fs::create_dir_all(path)?;
+            writable_paths.push(fs::canonicalize(path)?);
+        }
+
+        let mut plan = Plan::new(&self.root)?;
+        plan.workdir = cstring(&fs::canonicalize(workdir)?)?;
+        plan.tmpfs(Path::new("/tmp"))?;
+        plan.proc(Path::new("/proc"))?;
+        for device in DEVICES {
+            plan.bind(&Path::new("/dev").join(device), false)?;
+        }
+        for (link, target) in [
+            ("fd", "/proc/self/fd"),
+            ("stdin", "/proc/self/fd/0"),
+            ("stdout", "/proc/self/fd/1"),
+            ("stderr", "/proc/self/fd/2"),
+        ] {
+            plan.symlink(&Path::new("/dev").join(link), Path::new(target))?;
+        }
+        for path in &self.read_only {
+            match fs::read_link(path) {
+                Ok(target) => plan.symlink(path, &target)?,
+                Err(_) => plan.bind(path, true)?,
+            }
+        }
+        // Sorted so that a workspace below `/tmp` is mounted over the fresh tmpfs.
+        writable_paths.sort();
+        for path in &writable_paths {
+            plan.bind(path, false)?;
+        }
+        if self.seccomp {
+            plan.filter = seccomp_filter();
+        }
+
+        // SAFETY: `Plan::enter` only makes raw syscalls on data prepared up front,
+        // which is what is allowed between fork and exec.
+        unsafe {
+            command.pre_exec(move || plan.enter());
+        }
+        Ok(())
+    }
+
+    /// Runs a trivial command in the sandbox to find out whether the host supports it.
+    pub async fn check(&self) -> io::Result<()> {
+        let mut command = Command::new("true");
+        self.apply(&mut command, Path::new("/"), &[])?;
+        let status = command.status().await?;
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "sandboxed test command failed: {}",
+                status
+            )));
+        }
+        Ok(())
+    }
+}
+
+async fn sysroot() -> Option<PathBuf> {
+    let output = Command::new("rustc")
+        .args(["--print", "sysroot"])
+        .output()
+        .await
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(PathBuf::from(
+        String::from_utf8_lossy(&output.stdout).trim().to_string(),
+    ))
+}
+
+fn cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(io::Error::other)
+}
+
+/// Everything the child does to enter the sandbox, prepared before forking.
+struct Plan {
+    root: PathBuf,
+    root_c: CString,
+    uid_map: String,
+    gid_map: String,
+    steps: Vec<Step>,
+    created: HashSet<PathBuf>,
+    workdir: CString,
+    filter: Option<Vec<libc::sock_filter>>,
+}
+
+enum Step {
+    Mkdir(CString),
+    Touch(CString),
+    Symlink {
+        target: CString,
+        link: CString,
+    },
+    Bind {
+        src: CString,
+        dst: CString,
+        read_only: bool,
+    },
+    Tmpfs(CString),
+    Proc(CString),
+}
+
+impl Plan {
+    fn new(root: &Path) -> io::Result<Plan> {
+        // SAFETY: getuid and getgid cannot fail.
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        Ok(Plan {
+            root: root.to_path_buf(),
+            root_c: cstring(root)?,
+            uid_map: format!("{} {} 1", uid, uid),
+            gid_map: format!("{} {} 1", gid, gid),
+            steps: Vec::new(),
+            created: HashSet::new(),
+            workdir: CString::new("/").unwrap(),
+            filter: None,
+        })
+    }
+
+    /// The location of sandbox path `path` before the root is switched.
+    fn staged(&self, path: &Path) -> PathBuf {
+        self.root.join(path.strip_prefix("/").unwrap_or(path))
+    }
+
+    /// Creates the mount point for `path` and its parents inside the new root.
+    fn mount_point(&mut self, path: &Path, is_dir: bool) -> io::Result<CString> {
+        let parents: Vec<&Path> = path.ancestors().skip(1).collect();
+        for parent in parents.into_iter().rev() {
+            if parent.parent().is_some() && self.created.insert(parent.to_path_buf()) {
+                self.steps.push(Step::Mkdir(cstring(&self.staged(parent))?));
+            }
+        }
+        let staged = cstring(&self.staged(path))?;
+        if self.created.insert(path.to_path_buf()) {
+            self.steps.push(if is_dir {
+                Step::Mkdir(staged.clone())
+            } else {
+                Step::Touch(staged.clone())
+            });
+        }
+        Ok(staged)
+    }
+
+    fn bind(&mut self, path: &Path, read_only: bool) -> io::Result<()> {
+        let Ok(metadata) = fs::metadata(path) else {
+            return Ok(());
+        };
+        let dst = self.mount_point(path, metadata.is_dir())?;
+        self.steps.push(Step::Bind {
+            src: cstring(path)?,
+            dst,
+            read_only,
+        });
+        Ok(())
+    }
+
+    fn symlink(&mut self, link: &Path, target: &Path) -> io::Result<()> {
+        if let Some(parent) = link.parent() {
+            self.mount_point(parent, true)?;
+        }
+        self.created.insert(link.to_path_buf());
+        self.steps.push(Step::Symlink {
+            target: cstring(target)?,
+            link: cstring(&self.staged(link))?,
+        });
+        Ok(())
+    }
+
+    fn tmpfs(&mut self, path: &Path) -> io::Result<()> {
+        let dst = self.mount_point(path, true)?;
+        self.steps.push(Step::Tmpfs(dst));
+        Ok(())
+    }
+
+    fn proc(&mut self, path: &Path) -> io::Result<()> {
+        let dst = self.mount_point(path, true)?;
+        self.steps.push(Step::Proc(dst));
+        Ok(())
+    }
+
+    /// Runs in the forked child, right before cargo is executed.
+    fn enter(&self) -> io::Result<()> {
+        // SAFETY: plain syscalls on NUL-terminated strings and buffers owned by `self`.
+        unsafe {
+            check(libc::unshare(
+                libc::CLONE_NEWUSER
+                    | libc::CLONE_NEWNS
+                    | libc::CLONE_NEWPID
+                    | libc::CLONE_NEWNET
+                    | libc::CLONE_NEWIPC
+                    | libc::CLONE_NEWUTS,
+            ))?;
+            // Older kernels have no setgroups file, and need no denial to map groups.
+            let _ = write_file(c"/proc/self/setgroups", b"deny");
+            write_file(c"/proc/self/uid_map", self.uid_map.as_bytes())?;
+            write_file(c"/proc/self/gid_map", self.gid_map.as_bytes())?;
+
+            // A new PID namespace only applies to children: fork once more so the
+            // build gets PID 1, and stay behind to relay its exit status.
+            match libc::fork() {
+                -1 => return Err(io::Error::last_os_error()),
+                0 => {}
+                pid => relay_exit(pid),
+            }
+            // Killing the relay, e.g. on cancellation, takes the namespace down with it.
+            check(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL))?;
+
+            check(libc::mount(
+                std::ptr::null(),
+                c"/".as_ptr(),
+                std::ptr::null(),
+                libc::MS_REC | libc::MS_PRIVATE,
+                std::ptr::null(),
+            ))?;
+            check(libc::mount(
+                c"tmpfs".as_ptr(),
+                self.root_c.as_ptr(),
+                c"tmpfs".as_ptr(),
+                libc::MS_NOSUID | libc::MS_NODEV,
+                c"mode=0755".as_ptr().cast(),
+            ))?;
+            for step in &self.steps {
+                step.run()?;
+            }
+            check(libc::mount(
+                std::ptr::null(),
+                self.root_c.as_ptr(),
+                std::ptr::null(),
+                libc::MS_REMOUNT | libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV,
+                std::ptr::null(),
+            ))?;
+
+            check(libc::chdir(self.root_c.as_ptr()))?;
+            check(
+                libc::syscall(libc::SYS_pivot_root, c".".as_ptr(), c".".as_ptr()) as libc::c_int,
+            )?;
+            check(libc::umount2(c".".as_ptr(), libc::MNT_DETACH))?;
+            check(libc::chdir(self.workdir.as_ptr()))?;
+
+            check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+            if let Some(filter) = &self.filter {
+                let program = libc::sock_fprog {
+                    len: filter.len() as libc::c_ushort,
+                    filter: filter.as_ptr().cast_mut(),
+                };
+                check(libc::prctl(
+                    libc::PR_SET_SECCOMP,
+                    libc::SECCOMP_MODE_FILTER,
+                    &program as *const libc::sock_fprog,
+                ))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Step {
+    unsafe fn run(&self) -> io::Result<()> {
+        unsafe {
+            match self {
+                Step::Mkdir(path) => {
+                    if libc::mkdir(path.as_ptr(), 0o755) != 0
+                        && io::Error::last_os_error().raw_os_error() != Some(libc::EEXIST)
+                    {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Step::Touch(path) => {
+                    let fd = check(libc::open(
+                        path.as_ptr(),
+                        libc::O_CREAT | libc::O_WRONLY | libc::O_CLOEXEC,
+                        0o644,
+                    ))?;
+                    libc::close(fd);
+                }
+                Step::Symlink { target, link } => {
+                    check(libc::symlink(target.as_ptr(), link.as_ptr()))?;
+                }
+                Step::Bind {
+                    src,
+                    dst,
+                    read_only,
+                } => {
+                    check(libc::mount(
+                        src.as_ptr(),
+                        dst.as_ptr(),
+                        std::ptr::null(),
+                        libc::MS_BIND | libc::MS_REC,
+                        std::ptr::null(),
+                    ))?;
+                    if *read_only {
+                        // Flags the host mount was made with are locked in a user
+                        // namespace and must be kept when remounting.
+                        let mut stat: libc::statvfs = std::mem::zeroed();
+                        check(libc::statvfs(src.as_ptr(), &mut stat))?;
+                        let locked = stat.f_flag
+                            & (libc::ST_NOSUID
+                                | libc::ST_NODEV
+                                | libc::ST_NOEXEC
+                                | libc::ST_NOATIME
+                                | libc::ST_NODIRATIME
+                                | libc::ST_RELATIME);
+                        check(libc::mount(
+                            std::ptr::null(),
+                            dst.as_ptr(),
+                            std::ptr::null(),
+                            libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY | locked,
+                            std::ptr::null(),
+                        ))?;
+                    }
+                }
+                Step::Tmpfs(path) => {
+                    check(libc::mount(
+                        c"tmpfs".as_ptr(),
+                        path.as_ptr(),
+                        c"tmpfs".as_ptr(),
+                        libc::MS_NOSUID | libc::MS_NODEV,
+                        c"mode=1777".as_ptr().cast(),
+                    ))?;
+                }
+                Step::Proc(path) => {
+                    check(libc::mount(
+                        c"proc".as_ptr(),
+                        path.as_ptr(),
+                        c"proc".as_ptr(),
+                        libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+                        std::ptr::null(),
+                    ))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Waits for the sandboxed child and exits with its status. Never returns.
+unsafe fn relay_exit(pid: libc::pid_t) -> ! {
+    unsafe {
+        // Release the pipe std uses to report exec failures, or the parent's spawn
+        // would block until the build finishes.
+        if libc::syscall(libc::SYS_close_range, 3, libc::c_uint::MAX, 0) != 0 {
+            for fd in 3..1024 {
+                libc::close(fd);
+            }
+        }
+
+        let mut status = 0;
+        while libc::waitpid(pid, &mut status, 0) == -1 {
+            if io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+                libc::_exit(127);
+            }
+        }
+        if libc::WIFEXITED(status) {
+            libc::_exit(libc::WEXITSTATUS(status));
+        }
+        libc::_exit(128 + libc::WTERMSIG(status));
+    }
+}
+
+unsafe fn write_file(path: &std::ffi::CStr, contents: &[u8]) -> io::Result<()> {
+    unsafe {
+        let fd = check(libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC))?;
+        let written = libc::write(fd, contents.as_ptr().cast(), contents.len());
+        libc::close(fd);
+        if written != contents.len() as isize {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Syscalls that let a process escape or inspect its surroundings, or that only an
+/// administrator needs. They fail with `EPERM` under the seccomp filter.
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_kexec_load,
+    libc::SYS_reboot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_acct,
+    libc::SYS_quotactl,
+];
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// A classic BPF program denying [`DENIED_SYSCALLS`], for the native architecture.
+fn seccomp_filter() -> Option<Vec<libc::sock_filter>> {
+    fn stmt(code: u32, k: u32) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+    fn jump(code: u32, k: u32, jt: usize) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt: jt as u8,
+            jf: 0,
+            k,
+        }
+    }
+
+    let arch = AUDIT_ARCH?;
+    let denied = DENIED_SYSCALLS.len();
+    // Offsets into `struct seccomp_data`.
+    let (nr_offset, arch_offset) = (0, 4);
+
+    let mut filter = vec![
+        stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, arch_offset),
+        jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, arch, 1),
+        stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, nr_offset),
+    ];
+    if cfg!(target_arch = "x86_64") {
+        // x32 syscalls share the architecture but not the numbers; deny them all.
+        filter.push(jump(
+            libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+            0x4000_0000,
+            denied + 1,
+        ));
+    }
+    for (i, nr) in DENIED_SYSCALLS.iter().enumerate() {
+        filter.push(jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            *nr as u32,
+            denied - i,
+        ));
+    }
+    filter.push(stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW));
+    filter.push(stmt(
+        libc::BPF_RET | libc::BPF_K,
+        libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+    ));
+    Some(filter)
+}
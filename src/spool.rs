@@ -0,0 +1,49 @@
+//! Writing request bodies to disk as they arrive, so large uploads never sit in memory.
+
+use axum::body::{Body, Bytes};
+use futures_util::StreamExt;
+use http_body_util::{BodyExt, Limited};
+use std::{io, path::Path};
+use tempfile::NamedTempFile;
+use tokio::{fs::File, io::AsyncWriteExt};
+
+pub enum SpoolError {
+    /// The body is larger than the configured maximum.
+    TooLarge(u64),
+    /// The client's body stream failed, e.g. because it disconnected.
+    Read(String),
+    Io(io::Error),
+}
+
+/// Streams `body` into a new temporary file in `dir`, giving up once more than
+/// `max_bytes` have arrived.
+pub async fn spool(body: Body, dir: &Path, max_bytes: u64) -> Result<NamedTempFile, SpoolError> {
+    let spooled = NamedTempFile::new_in(dir).map_err(SpoolError::Io)?;
+    let mut file = File::from_std(spooled.reopen().map_err(SpoolError::Io)?);
+    let mut written = 0u64;
+
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| SpoolError::Read(e.to_string()))?;
+        written += chunk.len() as u64;
+        if written > max_bytes {
+            return Err(SpoolError::TooLarge(max_bytes));
+        }
+        file.write_all(&chunk).await.map_err(SpoolError::Io)?;
+    }
+    file.flush().await.map_err(SpoolError::Io)?;
+
+    Ok(spooled)
+}
+
+/// Buffers a small body in memory, rejecting it once it exceeds `max_bytes`.
+pub async fn collect_limited(body: Body, max_bytes: u64) -> Result<Bytes, SpoolError> {
+    let limit = usize::try_from(max_bytes).unwrap_or(usize::MAX);
+    match Limited::new(body, limit).collect().await {
+        Ok(collected) => Ok(collected.to_bytes()),
+        Err(e) if e.is::<http_body_util::LengthLimitError>() => {
+            Err(SpoolError::TooLarge(max_bytes))
+        }
+        Err(e) => Err(SpoolError::Read(e.to_string())),
+    }
+}
@@ -0,0 +1,118 @@
+//! Structured logging through `tracing`, and the request IDs that tie a request's
+//! log lines and its response together.
+//!
+//! Each request runs in a `request` span carrying its ID, and each build in a `job`
+//! span nested under the request that submitted it, so every line a build logs can
+//! be traced back to its request.
+
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{HeaderValue, Response},
+    middleware::Next,
+};
+use std::{
+    env,
+    io::{self, IsTerminal},
+    str::FromStr,
+    time::Instant,
+};
+use tracing::{Instrument, info, info_span, warn};
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest client-supplied request ID that is passed through unchanged.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One line per event, with span fields inline.
+    #[default]
+    Text,
+    /// Multi-line, human-oriented output.
+    Pretty,
+    /// One JSON object per event, including the fields of every enclosing span.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Installs the global subscriber, filtered by `RUST_LOG` (default `info`) and
+/// formatted per `WORKER_LOG_FORMAT`.
+///
+/// Runs before the rest of the configuration is read, so problems found there are
+/// already logged in the chosen format.
+pub fn init() {
+    let requested = env::var("WORKER_LOG_FORMAT").ok();
+    let format = requested
+        .as_deref()
+        .map_or(Ok(LogFormat::default()), str::parse);
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(io::stdout().is_terminal());
+    match format.unwrap_or_default() {
+        LogFormat::Text => builder.init(),
+        LogFormat::Pretty => builder.pretty().init(),
+        LogFormat::Json => builder
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(true)
+            .init(),
+    }
+
+    if format.is_err() {
+        warn!(
+            value = %requested.unwrap_or_default(),
+            "Ignoring invalid WORKER_LOG_FORMAT"
+        );
+    }
+}
+
+/// Runs each request in a span named after its ID and echoes the ID in the
+/// response. The client's `X-Request-Id` is used when it has one.
+pub async fn trace_requests(req: Request, next: Next) -> Response<Body> {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    let span = info_span!(
+        "request",
+        request_id = %request_id,
+        method = %req.method(),
+        path = %req.uri().path(),
+    );
+    let started = Instant::now();
+    let mut response = next.run(req).instrument(span.clone()).await;
+
+    span.in_scope(|| {
+        info!(
+            status = response.status().as_u16(),
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "Request finished"
+        )
+    });
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
@@ -0,0 +1,65 @@
+use serde::Serialize;
+use tokio::process::Command;
+
+/// What the local Rust toolchain can build, probed at startup and on
+/// `GET /capabilities?refresh=true`.
+#[derive(Clone, PartialEq, Serialize)]
+pub struct Toolchain {
+    pub host: String,
+    pub targets: Vec<String>,
+    /// Full `rustc -vV` output, which identifies the compiler build exactly.
+    pub rustc_version: String,
+}
+
+impl Toolchain {
+    pub async fn detect() -> Toolchain {
+        let rustc_version = rustc_version().await.unwrap_or_default();
+        let host = rustc_version
+            .lines()
+            .find_map(|line| line.strip_prefix("host: "))
+            .map(|host| host.trim().to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+
+        // Without rustup only the host's standard library is available.
+        let targets = installed_rustup_targets()
+            .await
+            .unwrap_or_else(|| vec![host.clone()]);
+
+        Toolchain {
+            host,
+            targets,
+            rustc_version,
+        }
+    }
+
+    pub fn supports_target(&self, triple: &str) -> bool {
+        self.targets.iter().any(|t| t == triple)
+    }
+}
+
+async fn rustc_version() -> Option<String> {
+    let output = Command::new("rustc").arg("-vV").output().await.ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+async fn installed_rustup_targets() -> Option<Vec<String>> {
+    let output = Command::new("rustup")
+        .args(["target", "list", "--installed"])
+        .output()
+        .await
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect(),
+    )
+}
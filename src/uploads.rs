@@ -0,0 +1,439 @@
+//! Delta uploads: clients describe their workspace with a manifest of content
+//! hashes, upload only the blobs the worker does not already have, and the worker
+//! materializes the tree from its local blob store.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tempfile::NamedTempFile;
+use uuid::Uuid;
+
+use crate::extract::{
+    ExtractLimits, check_link_target, link_stays_inside, prepare_path, safe_relative_path,
+    set_mode, symlink,
+};
+use crate::util::{dir_size, hex};
+use walkdir::WalkDir;
+
+/// How long a registered manifest can be built from.
+const MANIFEST_RETENTION: Duration = Duration::from_secs(3600);
+
+#[derive(Deserialize)]
+pub struct UploadManifest {
+    pub files: Vec<ManifestFile>,
+}
+
+/// One entry of a workspace: a regular file (`sha256`) or a symlink (`symlink`).
+#[derive(Deserialize)]
+pub struct ManifestFile {
+    pub path: String,
+    pub sha256: Option<String>,
+    pub symlink: Option<String>,
+    /// Unix permission bits; only the executable bits are honored.
+    #[serde(default)]
+    pub mode: Option<u32>,
+    /// Modification time in seconds since the Unix epoch. Keeping it stable lets
+    /// cargo's fingerprints treat unchanged files as fresh.
+    #[serde(default)]
+    pub mtime: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct UploadSession {
+    pub upload_id: String,
+    /// Blob hashes to `PUT /blobs/{sha256}` before building.
+    pub missing: Vec<String>,
+}
+
+pub enum MaterializeError {
+    /// The upload ID was never issued, or is older than [`MANIFEST_RETENTION`].
+    UnknownUpload,
+    /// The manifest exceeds the unpack limits.
+    Rejected(String),
+    /// Blobs that are referenced by the manifest but not in the store.
+    MissingBlobs(Vec<String>),
+    /// An entry could not be created safely, e.g. because it lies beneath a symlink.
+    InvalidEntry(String, String),
+    Io(io::Error),
+}
+
+pub enum BlobError {
+    InvalidHash,
+    /// The uploaded content does not hash to the name it was uploaded under.
+    HashMismatch(String),
+    Io(io::Error),
+}
+
+pub struct Uploads {
+    blobs_dir: PathBuf,
+    max_bytes: u64,
+    /// The limits tarball uploads are unpacked under, applied to manifests.
+    limits: ExtractLimits,
+    stored_bytes: AtomicU64,
+    manifests: Mutex<HashMap<String, (Instant, Arc<UploadManifest>)>>,
+    /// Serializes eviction against concurrent inserts.
+    evict_lock: Mutex<()>,
+}
+
+impl UploadManifest {
+    /// Checks the manifest's paths and, against `limits`, its entry count and the
+    /// sizes of the blobs `blob_size` knows. Blobs it does not know are skipped.
+    pub fn validate(
+        &self,
+        limits: &ExtractLimits,
+        blob_size: impl Fn(&str) -> Option<u64>,
+    ) -> Result<(), String> {
+        if self.files.len() as u64 > limits.max_entries {
+            return Err(format!(
+                "Manifest has more than {} entries",
+                limits.max_entries
+            ));
+        }
+
+        let mut total_bytes = 0u64;
+        for file in &self.files {
+            let Some(path) = safe_relative_path(&file.path) else {
+                return Err(format!("Invalid path: {}", file.path));
+            };
+            match (&file.sha256, &file.symlink) {
+                (Some(hash), None) if is_valid_hash(hash) => {
+                    let size = blob_size(hash).unwrap_or(0);
+                    if size > limits.max_file_bytes {
+                        return Err(format!(
+                            "File is larger than {} bytes: {}",
+                            limits.max_file_bytes, file.path
+                        ));
+                    }
+                    total_bytes += size;
+                    if total_bytes > limits.max_total_bytes {
+                        return Err(format!(
+                            "Manifest unpacks to more than {} bytes",
+                            limits.max_total_bytes
+                        ));
+                    }
+                }
+                (Some(_), None) => return Err(format!("Invalid sha256 for {}", file.path)),
+                (None, Some(target)) if link_stays_inside(&path, target) => {}
+                (None, Some(_)) => {
+                    return Err(format!("Symlink escapes the workspace: {}", file.path));
+                }
+                _ => {
+                    return Err(format!(
+                        "Exactly one of sha256 and symlink is required: {}",
+                        file.path
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn blob_hashes(&self) -> BTreeSet<&str> {
+        self.files
+            .iter()
+            .filter_map(|file| file.sha256.as_deref())
+            .collect()
+    }
+}
+
+impl Uploads {
+    pub fn new(blobs_dir: PathBuf, max_bytes: u64, limits: ExtractLimits) -> io::Result<Uploads> {
+        fs::create_dir_all(&blobs_dir)?;
+        let stored_bytes = dir_size(&blobs_dir);
+
+        Ok(Uploads {
+            blobs_dir,
+            max_bytes,
+            limits,
+            stored_bytes: AtomicU64::new(stored_bytes),
+            manifests: Mutex::new(HashMap::new()),
+            evict_lock: Mutex::new(()),
+        })
+    }
+
+    /// Validates a manifest against the unpack limits, using the sizes of the blobs
+    /// already in the store.
+    pub fn validate(&self, manifest: &UploadManifest) -> Result<(), String> {
+        manifest.validate(&self.limits, |hash| {
+            fs::metadata(self.blob_path(hash))
+                .ok()
+                .map(|meta| meta.len())
+        })
+    }
+
+    /// Registers a validated manifest and reports which of its blobs must be uploaded.
+    pub fn register(&self, manifest: UploadManifest) -> UploadSession {
+        let missing = manifest
+            .blob_hashes()
+            .into_iter()
+            .filter(|hash| !self.blob_path(hash).exists())
+            .map(str::to_string)
+            .collect();
+
+        let upload_id = Uuid::new_v4().to_string();
+        let mut manifests = self.manifests.lock().unwrap();
+        manifests.retain(|_, (registered, _)| registered.elapsed() < MANIFEST_RETENTION);
+        manifests.insert(upload_id.clone(), (Instant::now(), Arc::new(manifest)));
+
+        UploadSession { upload_id, missing }
+    }
+
+    /// Where uploaded blobs are spooled before [`Uploads::put_blob`], on the same
+    /// filesystem as the store.
+    pub fn staging_dir(&self) -> &Path {
+        &self.blobs_dir
+    }
+
+    /// Moves a spooled upload into the store after checking that it hashes to `hash`.
+    pub fn put_blob(&self, hash: &str, staged: NamedTempFile) -> Result<(), BlobError> {
+        if !is_valid_hash(hash) {
+            return Err(BlobError::InvalidHash);
+        }
+        let mut contents = Sha256::new();
+        let size = io::copy(&mut staged.reopen().map_err(BlobError::Io)?, &mut contents)
+            .map_err(BlobError::Io)?;
+        let actual = hex(&contents.finalize());
+        if actual != hash {
+            return Err(BlobError::HashMismatch(actual));
+        }
+
+        let path = self.blob_path(hash);
+        if path.exists() {
+            return Ok(());
+        }
+        fs::create_dir_all(path.parent().expect("blob paths have a parent"))
+            .map_err(BlobError::Io)?;
+        staged.persist(&path).map_err(|e| BlobError::Io(e.error))?;
+
+        let total = self.stored_bytes.fetch_add(size, Ordering::Relaxed) + size;
+        if total > self.max_bytes {
+            self.evict();
+        }
+        Ok(())
+    }
+
+    /// Recreates the tree described by upload `id` inside the empty directory `dest`.
+    pub fn materialize(&self, id: &str, dest: &Path) -> Result<(), MaterializeError> {
+        let manifest = match self.manifests.lock().unwrap().get(id) {
+            Some((registered, manifest)) if registered.elapsed() < MANIFEST_RETENTION => {
+                Arc::clone(manifest)
+            }
+            _ => return Err(MaterializeError::UnknownUpload),
+        };
+
+        let missing: Vec<String> = manifest
+            .blob_hashes()
+            .into_iter()
+            .filter(|hash| !self.blob_path(hash).exists())
+            .map(str::to_string)
+            .collect();
+        if !missing.is_empty() {
+            return Err(MaterializeError::MissingBlobs(missing));
+        }
+        // Now that every blob is present, sizes are checked in full.
+        self.validate(&manifest)
+            .map_err(MaterializeError::Rejected)?;
+
+        for file in &manifest.files {
+            let relative = safe_relative_path(&file.path).expect("validated on register");
+            let path = prepare_path(dest, &relative)
+                .map_err(|reason| MaterializeError::InvalidEntry(file.path.clone(), reason))?;
+
+            if let Some(target) = &file.symlink {
+                check_link_target(dest, &relative, target)
+                    .map_err(|reason| MaterializeError::InvalidEntry(file.path.clone(), reason))?;
+                symlink(target, &path).map_err(MaterializeError::Io)?;
+                continue;
+            }
+
+            let hash = file.sha256.as_deref().expect("validated on register");
+            let blob = self.blob_path(hash);
+            // Copied rather than hard-linked so build scripts cannot corrupt the store.
+            // A concurrent upload may have evicted the blob since it was checked above.
+            fs::copy(&blob, &path).map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => MaterializeError::MissingBlobs(vec![hash.to_string()]),
+                _ => MaterializeError::Io(e),
+            })?;
+
+            // Marks the blob as recently used for eviction.
+            if let Ok(blob) = File::options().write(true).open(&blob) {
+                let _ = blob.set_modified(SystemTime::now());
+            }
+
+            let file_handle = File::options()
+                .write(true)
+                .open(&path)
+                .map_err(MaterializeError::Io)?;
+            if let Some(mtime) = file.mtime {
+                file_handle
+                    .set_modified(UNIX_EPOCH + Duration::from_secs(mtime))
+                    .map_err(MaterializeError::Io)?;
+            }
+            set_mode(&path, file.mode.unwrap_or(0o644)).map_err(MaterializeError::Io)?;
+        }
+
+        Ok(())
+    }
+
+    fn blob_path(&self, hash: &str) -> PathBuf {
+        self.blobs_dir.join(&hash[..2]).join(hash)
+    }
+
+    /// Deletes least recently used blobs until the store fits in `max_bytes`.
+    fn evict(&self) {
+        let _guard = self.evict_lock.lock().unwrap();
+
+        let mut blobs: Vec<(SystemTime, u64, PathBuf)> = WalkDir::new(&self.blobs_dir)
+            .min_depth(2)
+            .max_depth(2)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| {
+                let meta = entry.metadata().ok()?;
+                Some((meta.modified().ok()?, meta.len(), entry.into_path()))
+            })
+            .collect();
+
+        let mut total: u64 = blobs.iter().map(|(_, size, _)| size).sum();
+        blobs.sort();
+        for (_, size, path) in blobs {
+            if total <= self.max_bytes {
+                break;
+            }
+            if fs::remove_file(&path).is_ok() {
+                total -= size;
+            }
+        }
+        self.stored_bytes.store(total, Ordering::Relaxed);
+    }
+}
+
+fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: ExtractLimits = ExtractLimits {
+        max_total_bytes: 1024,
+        max_entries: 16,
+        max_file_bytes: 512,
+    };
+
+    fn hash(digit: char) -> String {
+        digit.to_string().repeat(64)
+    }
+
+    fn file(path: &str, sha256: &str) -> ManifestFile {
+        ManifestFile {
+            path: path.to_string(),
+            sha256: Some(sha256.to_string()),
+            symlink: None,
+            mode: None,
+            mtime: None,
+        }
+    }
+
+    fn symlink(path: &str, target: &str) -> ManifestFile {
+        ManifestFile {
+            sha256: None,
+            symlink: Some(target.to_string()),
+            ..file(path, "")
+        }
+    }
+
+    /// Validates `files`, with blobs named `1`…`9` repeated being 100 times that many bytes.
+    fn validate(files: Vec<ManifestFile>) -> Result<(), String> {
+        UploadManifest { files }.validate(&LIMITS, |hash| {
+            let digit = hash.chars().next()?.to_digit(10)?;
+            Some(u64::from(digit) * 100)
+        })
+    }
+
+    fn assert_rejected(files: Vec<ManifestFile>, expected: &str) {
+        let reason = validate(files).expect_err("manifest was accepted");
+        assert!(reason.contains(expected), "{reason:?} lacks {expected:?}");
+    }
+
+    #[test]
+    fn accepts_files_and_links_inside_the_workspace() {
+        validate(vec![
+            file("Cargo.toml", &hash('1')),
+            file("src/main.rs", &hash('2')),
+            file("unknown.rs", &hash('a')),
+            symlink("src/lib.rs", "main.rs"),
+            symlink("src/up", "../Cargo.toml"),
+        ])
+        .unwrap();
+    }
+
+    #[test]
+    fn rejects_path_traversal() {
+        assert_rejected(vec![file("../escape", &hash('1'))], "Invalid path");
+        assert_rejected(vec![file("a/../../escape", &hash('1'))], "Invalid path");
+    }
+
+    #[test]
+    fn rejects_absolute_paths() {
+        assert_rejected(vec![file("/tmp/escape", &hash('1'))], "Invalid path");
+        assert_rejected(vec![symlink("l", "/etc")], "Symlink escapes");
+    }
+
+    #[test]
+    fn rejects_symlinks_leaving_the_root() {
+        assert_rejected(vec![symlink("a/l", "../..")], "Symlink escapes");
+    }
+
+    #[test]
+    fn requires_exactly_one_of_sha256_and_symlink() {
+        let both = ManifestFile {
+            symlink: Some("x".to_string()),
+            ..file("both", &hash('1'))
+        };
+        let neither = ManifestFile {
+            sha256: None,
+            ..file("neither", "")
+        };
+        assert_rejected(vec![both], "Exactly one of sha256 and symlink");
+        assert_rejected(vec![neither], "Exactly one of sha256 and symlink");
+    }
+
+    #[test]
+    fn rejects_malformed_hashes() {
+        assert_rejected(vec![file("a", &hash('A'))], "Invalid sha256");
+        assert_rejected(vec![file("a", &hash('1')[1..])], "Invalid sha256");
+        assert_rejected(vec![file("a", &hash('g'))], "Invalid sha256");
+    }
+
+    #[test]
+    fn enforces_size_limits() {
+        assert_rejected(
+            vec![file("big", &hash('6'))],
+            "File is larger than 512 bytes",
+        );
+        assert_rejected(
+            vec![
+                file("a", &hash('5')),
+                file("b", &hash('5')),
+                file("c", &hash('1')),
+            ],
+            "Manifest unpacks to more than 1024 bytes",
+        );
+        let too_many = (0..17)
+            .map(|i| file(&format!("f{i}"), &hash('a')))
+            .collect();
+        assert_rejected(too_many, "more than 16 entries");
+    }
+}
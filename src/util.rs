@@ -0,0 +1,20 @@
+//! Small helpers shared by several modules.
+
+use std::path::Path;
+use walkdir::WalkDir;
+
+/// Lowercase hexadecimal encoding, as used for SHA-256 digests throughout the worker.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Total size of the regular files below `dir`; unreadable entries are skipped.
+pub fn dir_size(dir: &Path) -> u64 {
+    WalkDir::new(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|meta| meta.is_file())
+        .map(|meta| meta.len())
+        .sum()
+}
@@ -0,0 +1,199 @@
+//! Persistent workspaces for incremental rebuilds.
+//!
+//! A client opts in by sending a `workspace_key`. Each key gets a directory holding
+//! the most recent sources (`src/`) and a `target/` directory that is reused across
+//! builds. The sources live at a stable path because cargo's fingerprints include
+//! the absolute workspace path; unpacking to a fresh temp dir each time would make
+//! every dependency look dirty.
+
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+use tracing::{info, warn};
+
+use crate::util::{dir_size, hex};
+
+/// Touched on every use; its mtime orders workspaces for eviction.
+const LAST_USED_MARKER: &str = ".last-used";
+
+/// One lock per workspace directory, held for the whole build. An entry is removed
+/// once nothing holds or waits for it.
+type Locks = Arc<Mutex<HashMap<String, Arc<AsyncMutex<()>>>>>;
+
+pub struct Workspaces {
+    root: PathBuf,
+    max_bytes: u64,
+    locks: Locks,
+}
+
+/// Exclusive use of a persistent workspace, released on drop.
+pub struct WorkspaceLease {
+    dir: PathBuf,
+    locks: Locks,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for WorkspaceLease {
+    fn drop(&mut self) {
+        self.guard.take();
+        remove_unused_locks(&self.locks);
+    }
+}
+
+impl WorkspaceLease {
+    pub fn src_dir(&self) -> PathBuf {
+        self.dir.join("src")
+    }
+
+    pub fn target_dir(&self) -> PathBuf {
+        self.dir.join("target")
+    }
+
+    /// Replaces the workspace's sources with a freshly unpacked tree.
+    ///
+    /// `unpacked` must be on the same filesystem as the workspace root.
+    pub async fn replace_sources(&self, unpacked: &Path) -> io::Result<()> {
+        let src = self.src_dir();
+        let unpacked = unpacked.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            if src.exists() {
+                fs::remove_dir_all(&src)?;
+            }
+            fs::rename(unpacked, &src)
+        })
+        .await?
+    }
+}
+
+impl Workspaces {
+    pub fn new(root: PathBuf, max_bytes: u64) -> io::Result<Workspaces> {
+        fs::create_dir_all(&root)?;
+        Ok(Workspaces {
+            root,
+            max_bytes,
+            locks: Locks::default(),
+        })
+    }
+
+    /// Waits for exclusive use of the workspace identified by the client's `key`.
+    pub async fn acquire(&self, key: &str) -> io::Result<WorkspaceLease> {
+        let name = dir_name(key);
+        let lock = Arc::clone(self.locks.lock().unwrap().entry(name.clone()).or_default());
+        let guard = lock.lock_owned().await;
+
+        let dir = self.root.join(&name);
+        fs::create_dir_all(&dir)?;
+        File::create(dir.join(LAST_USED_MARKER))?.set_modified(SystemTime::now())?;
+
+        Ok(WorkspaceLease {
+            dir,
+            locks: Arc::clone(&self.locks),
+            guard: Some(guard),
+        })
+    }
+
+    /// Deletes least recently used workspaces until the total fits in `max_bytes`.
+    /// Workspaces with a build in progress are never evicted.
+    pub fn evict(&self) {
+        let Ok(dirs) = fs::read_dir(&self.root) else {
+            return;
+        };
+        let mut workspaces: Vec<(SystemTime, u64, String)> = dirs
+            .flatten()
+            .filter_map(|entry| {
+                let used = fs::metadata(entry.path().join(LAST_USED_MARKER))
+                    .and_then(|meta| meta.modified())
+                    .unwrap_or(SystemTime::UNIX_EPOCH);
+                let name = entry.file_name().into_string().ok()?;
+                Some((used, dir_size(&entry.path()), name))
+            })
+            .collect();
+
+        let mut total: u64 = workspaces.iter().map(|(_, size, _)| size).sum();
+        workspaces.sort();
+
+        for (_, size, name) in workspaces {
+            if total <= self.max_bytes {
+                break;
+            }
+
+            let removed = {
+                let lock = Arc::clone(self.locks.lock().unwrap().entry(name.clone()).or_default());
+                let Ok(_guard) = lock.try_lock() else {
+                    continue;
+                };
+                fs::remove_dir_all(self.root.join(&name))
+            };
+            remove_unused_locks(&self.locks);
+            match removed {
+                Ok(()) => {
+                    info!(workspace = %name, bytes = size, "Evicted workspace");
+                    total = total.saturating_sub(size);
+                }
+                Err(e) => warn!(workspace = %name, error = ?e, "Failed to evict workspace"),
+            }
+        }
+    }
+}
+
+/// Forgets the locks no lease holds and no `acquire` waits for, including those of
+/// acquires that were abandoned while waiting.
+fn remove_unused_locks(locks: &Locks) {
+    locks
+        .lock()
+        .unwrap()
+        .retain(|_, lock| Arc::strong_count(lock) > 1);
+}
+
+/// Client keys are hashed so they can never name a path outside the root.
+fn dir_name(key: &str) -> String {
+    hex(&Sha256::digest(key.as_bytes())[..16])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    fn lock_count(workspaces: &Workspaces) -> usize {
+        workspaces.locks.lock().unwrap().len()
+    }
+
+    #[tokio::test]
+    async fn forgets_locks_nobody_holds() {
+        let tmp = TempDir::new().unwrap();
+        let workspaces = Workspaces::new(tmp.path().to_path_buf(), 0).unwrap();
+
+        let lease = workspaces.acquire("a").await.unwrap();
+        drop(workspaces.acquire("b").await.unwrap());
+        assert_eq!(lock_count(&workspaces), 1);
+        for dir in fs::read_dir(tmp.path()).unwrap() {
+            fs::write(dir.unwrap().path().join("data"), "x").unwrap();
+        }
+
+        // An acquire that gives up while waiting leaves its lock behind until the
+        // next release.
+        let waited = tokio::time::timeout(Duration::from_millis(20), workspaces.acquire("a"));
+        assert!(waited.await.is_err());
+
+        // Held by the lease, so neither evicted nor forgotten.
+        workspaces.evict();
+        assert!(lease.src_dir().parent().unwrap().exists());
+        assert_eq!(lock_count(&workspaces), 1);
+
+        drop(lease);
+        assert_eq!(lock_count(&workspaces), 0);
+
+        workspaces.evict();
+        assert_eq!(lock_count(&workspaces), 0);
+        assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 0);
+    }
+}